@@ -2,7 +2,7 @@ extern crate flame;
 
 use std::fs::File;
 
-pub fn main() {
+fn frame() {
     flame::start("update");
         flame::start("process inputs");
         flame::end("process inputs");
@@ -26,7 +26,21 @@ pub fn main() {
         flame::start("draw calls");
         flame::end("draw calls");
     flame::end("render");
+}
 
+pub fn main() {
+    for _ in 0 .. 60 {
+        frame();
+        flame::frame_mark();
+    }
+
+    let frames = flame::frames();
+    for slow in flame::slowest_frames(&frames, 3) {
+        println!("frame {}: {}ns", slow.number, slow.delta);
+    }
+
+    // Marking frames doesn't clear anything, the dumps below contain
+    // every frame.
     flame::dump_html(&mut File::create("out.html").unwrap()).unwrap();
     flame::dump_json(&mut File::create("out.json").unwrap()).unwrap();
     flame::dump_stdout();
@@ -0,0 +1,178 @@
+//! Procedural macros for [flame](https://docs.rs/flame).
+//!
+//! Don't depend on this crate directly, enable the `macros` feature of
+//! `flame` instead and use the re-exported attributes:
+//!
+//! ```ignore
+//! use flame::flame;
+//!
+//! #[flame]
+//! fn this_function_is_profiled() {
+//!     // ...
+//! }
+//!
+//! #[flame(name = "parser", collapse, enabled = PROFILE_PARSER)]
+//! impl Parser {
+//!     fn parse(&mut self) { /* timed as "my_crate::Parser::parse" */ }
+//!
+//!     #[noflame]
+//!     fn peek(&self) { /* not timed */ }
+//! }
+//! ```
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{quote, ToTokens};
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, Attribute, Block, Expr, ImplItem, ImplItemFn, Item, ItemFn, LitStr, Token};
+
+/// Options accepted by `#[flame(..)]`.
+///
+/// * `"name"` or `name = "name"` replaces the function name in the span name
+/// * `collapse` ends the span with `end_collapse` semantics
+/// * `enabled = EXPR` only records the span when the `bool` expression
+///   (usually a module level `const` or `static`) evaluates to `true`
+#[derive(Default, Clone)]
+struct Options {
+    name: Option<LitStr>,
+    collapse: bool,
+    enabled: Option<Expr>,
+}
+
+impl Parse for Options {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut options = Options::default();
+        while !input.is_empty() {
+            if input.peek(LitStr) {
+                options.name = Some(input.parse()?);
+            } else {
+                let key: syn::Ident = input.parse()?;
+                match key.to_string().as_str() {
+                    "name" => {
+                        input.parse::<Token![=]>()?;
+                        options.name = Some(input.parse()?);
+                    }
+                    "collapse" => options.collapse = true,
+                    "enabled" => {
+                        input.parse::<Token![=]>()?;
+                        options.enabled = Some(input.parse()?);
+                    }
+                    _ => return Err(syn::Error::new(key.span(), "expected one of `name`, `collapse` or `enabled`")),
+                }
+            }
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// Instruments a function, every method of an `impl` block or every
+/// function and `impl` block of an inline module.
+///
+/// The span is named `module_path::function` (or `module_path::Type::method`
+/// for methods).  `async fn`s are instrumented per poll, so the span never
+/// stays open across an `.await`.
+#[proc_macro_attribute]
+pub fn flame(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let options = parse_macro_input!(attr as Options);
+    let item = parse_macro_input!(item as Item);
+    instrument_item(item, &options).into()
+}
+
+/// Excludes a function, `impl` block or module from an enclosing
+/// `#[flame]` `impl` block or module.
+#[proc_macro_attribute]
+pub fn noflame(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    item
+}
+
+fn instrument_item(item: Item, options: &Options) -> TokenStream2 {
+    match item {
+        Item::Fn(mut f) => {
+            if !take_noflame(&mut f.attrs) {
+                let ItemFn { ref sig, ref mut block, .. } = f;
+                let name = span_name(options, None, &sig.ident);
+                **block = instrument_block(block, name, options, sig.asyncness.is_some());
+            }
+            f.into_token_stream()
+        }
+        Item::Impl(mut i) => {
+            if take_noflame(&mut i.attrs) {
+                return i.into_token_stream();
+            }
+            let self_ty = i.self_ty.to_token_stream().to_string().replace(' ', "");
+            for impl_item in &mut i.items {
+                if let ImplItem::Fn(ImplItemFn { ref mut attrs, ref sig, ref mut block, .. }) = *impl_item {
+                    if take_noflame(attrs) {
+                        continue;
+                    }
+                    let name = span_name(options, Some(&self_ty), &sig.ident);
+                    *block = instrument_block(block, name, options, sig.asyncness.is_some());
+                }
+            }
+            i.into_token_stream()
+        }
+        Item::Mod(mut m) => {
+            if take_noflame(&mut m.attrs) {
+                return m.into_token_stream();
+            }
+            if let Some((_, ref mut items)) = m.content {
+                for item in items.iter_mut() {
+                    let instrumented = instrument_item(item.clone(), options);
+                    *item = syn::parse2(instrumented).expect("instrumented item is not an item");
+                }
+            }
+            m.into_token_stream()
+        }
+        other => other.into_token_stream(),
+    }
+}
+
+fn take_noflame(attrs: &mut Vec<Attribute>) -> bool {
+    let before = attrs.len();
+    attrs.retain(|attr| !attr.path().is_ident("noflame"));
+    attrs.len() != before
+}
+
+fn span_name(options: &Options, self_ty: Option<&str>, ident: &syn::Ident) -> TokenStream2 {
+    if let Some(ref name) = options.name {
+        return quote!(#name);
+    }
+    let ident = ident.to_string();
+    match self_ty {
+        Some(self_ty) => quote!(concat!(module_path!(), "::", #self_ty, "::", #ident)),
+        None => quote!(concat!(module_path!(), "::", #ident)),
+    }
+}
+
+fn instrument_block(block: &Block, name: TokenStream2, options: &Options, is_async: bool) -> Block {
+    let enabled = match options.enabled {
+        Some(ref enabled) => quote!(#enabled),
+        None => quote!(true),
+    };
+    let collapse = options.collapse;
+
+    let body = if is_async {
+        quote!({
+            ::flame::instrument_with(
+                if #enabled { ::std::option::Option::Some(#name) } else { ::std::option::Option::None },
+                #collapse,
+                async move #block,
+            ).await
+        })
+    } else {
+        // The statements are spliced in rather than nesting the block, which
+        // would trigger `unused_braces` in the user's crate.
+        let start = if collapse { quote!(::flame::start_guard_collapse) } else { quote!(::flame::start_guard) };
+        let stmts = &block.stmts;
+        quote!({
+            let _flame_guard = if #enabled { ::std::option::Option::Some(#start(#name)) } else { ::std::option::Option::None };
+            #(#stmts)*
+        })
+    };
+    syn::parse2(body).expect("instrumented body is not a block")
+}
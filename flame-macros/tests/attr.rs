@@ -0,0 +1,214 @@
+#![deny(unused_braces)]
+
+use flame::flame;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+const ENABLED: bool = true;
+const DISABLED: bool = false;
+
+#[flame]
+fn plain() -> u32 {
+    nested()
+}
+
+#[flame]
+fn nested() -> u32 {
+    1
+}
+
+#[flame("custom name")]
+fn custom() {}
+
+#[flame(enabled = DISABLED)]
+fn switched_off() {}
+
+#[flame(enabled = ENABLED, collapse)]
+fn collapsed() {}
+
+#[flame]
+fn early_return(x: u32) -> Result<u32, ()> {
+    if x == 0 {
+        return Err(());
+    }
+    Ok(x)
+}
+
+struct Widget;
+
+#[flame]
+impl Widget {
+    fn update(&self) -> u32 {
+        self.helper()
+    }
+
+    #[noflame]
+    fn helper(&self) -> u32 {
+        2
+    }
+}
+
+#[flame]
+mod whole_module {
+    pub fn first() {
+        second()
+    }
+
+    fn second() {}
+
+    pub struct Untimed;
+
+    #[noflame]
+    impl Untimed {
+        pub fn run(&self) {
+            inner::third()
+        }
+    }
+
+    #[noflame]
+    pub mod inner {
+        pub fn third() {}
+    }
+}
+
+#[flame]
+async fn asynchronous(x: u32) -> u32 {
+    Yield(false).await;
+    x
+}
+
+struct Yield(bool);
+
+impl Future for Yield {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    fn raw() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker { raw() }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    let waker = unsafe { Waker::from_raw(raw()) };
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let Poll::Ready(out) = future.as_mut().poll(&mut cx) {
+            return out;
+        }
+    }
+}
+
+#[test]
+fn function_names_include_module_path() {
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        assert_eq!(plain(), 1);
+
+        let spans = flame::spans();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "attr::plain");
+        assert_eq!(spans[0].children.len(), 1);
+        assert_eq!(spans[0].children[0].name, "attr::nested");
+    });
+}
+
+#[test]
+fn options() {
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        flame::span_of("outer", || {
+            custom();
+            switched_off();
+            collapsed();
+            collapsed();
+        });
+
+        let spans = &flame::spans()[0].children;
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].name, "custom name");
+        assert_eq!(spans[1].name, "attr::collapsed");
+    });
+}
+
+#[test]
+fn early_returns_end_the_span() {
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        assert!(early_return(0).is_err());
+        assert_eq!(early_return(3), Ok(3));
+        assert_eq!(flame::spans().len(), 2);
+    });
+}
+
+#[test]
+fn impl_blocks() {
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        assert_eq!(Widget.update(), 2);
+
+        let spans = flame::spans();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "attr::Widget::update");
+        assert!(spans[0].children.is_empty());
+    });
+}
+
+#[test]
+fn modules() {
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        whole_module::first();
+
+        let spans = flame::spans();
+        assert_eq!(spans[0].name, "attr::whole_module::first");
+        assert_eq!(spans[0].children[0].name, "attr::whole_module::second");
+    });
+}
+
+#[test]
+fn noflame_items_in_modules() {
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        whole_module::Untimed.run();
+        assert!(flame::spans().is_empty());
+    });
+}
+
+#[test]
+fn async_fns_are_timed_per_poll() {
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        assert_eq!(block_on(asynchronous(5)), 5);
+
+        let spans = flame::spans();
+        assert_eq!(spans.len(), 2);
+        assert!(spans.iter().all(|s| s.name == "attr::asynchronous"));
+    });
+}
+
+#[test]
+fn flame_fn() {
+    fn named() {
+        flame::flame_fn!();
+    }
+
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        named();
+        let spans = flame::spans();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "attr::flame_fn::named");
+    });
+}
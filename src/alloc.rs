@@ -0,0 +1,197 @@
+//! Allocation tracking.
+//!
+//! Install `FlameAlloc` as the global allocator to have every span
+//! record the allocations that its thread made while it was running:
+//!
+//! ```
+//! extern crate flame;
+//!
+//! use std::alloc::System;
+//! use flame::alloc::FlameAlloc;
+//!
+//! #[global_allocator]
+//! static ALLOC: FlameAlloc<System> = FlameAlloc::new(System);
+//!
+//! fn main() {
+//!     flame::span_of("allocate", || vec![0u8; 1024]);
+//!     let stats = flame::spans()[0].alloc.unwrap();
+//!     assert!(stats.bytes_allocated >= 1024);
+//! }
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// The counters of one thread.  The allocator only ever touches these,
+/// never the thread's libraries, so that it can't re-enter them.
+struct Counters {
+    allocations: Cell<u64>,
+    deallocations: Cell<u64>,
+    bytes_allocated: Cell<u64>,
+    bytes_freed: Cell<u64>,
+    live: Cell<i64>,
+    peak: Cell<i64>,
+}
+
+thread_local! {
+    static COUNTERS: Counters = const {
+        Counters {
+            allocations: Cell::new(0),
+            deallocations: Cell::new(0),
+            bytes_allocated: Cell::new(0),
+            bytes_freed: Cell::new(0),
+            live: Cell::new(0),
+            peak: Cell::new(0),
+        }
+    };
+}
+
+/// A global allocator that counts the allocations of every thread
+/// before handing them to `A`.
+#[derive(Debug, Default)]
+pub struct FlameAlloc<A> {
+    inner: A,
+}
+
+impl<A> FlameAlloc<A> {
+    pub const fn new(inner: A) -> FlameAlloc<A> {
+        FlameAlloc { inner }
+    }
+}
+
+fn record_alloc(size: usize) {
+    if !INSTALLED.load(Ordering::Relaxed) {
+        INSTALLED.store(true, Ordering::Relaxed);
+    }
+    let _ = COUNTERS.try_with(|counters| {
+        counters.allocations.set(counters.allocations.get() + 1);
+        counters.bytes_allocated.set(counters.bytes_allocated.get() + size as u64);
+        let live = counters.live.get() + size as i64;
+        counters.live.set(live);
+        if live > counters.peak.get() {
+            counters.peak.set(live);
+        }
+    });
+}
+
+fn record_dealloc(size: usize) {
+    let _ = COUNTERS.try_with(|counters| {
+        counters.deallocations.set(counters.deallocations.get() + 1);
+        counters.bytes_freed.set(counters.bytes_freed.get() + size as u64);
+        counters.live.set(counters.live.get() - size as i64);
+    });
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for FlameAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        record_dealloc(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            record_dealloc(layout.size());
+            record_alloc(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// What a thread allocated while a span was running.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct AllocStats {
+    /// The number of allocations, counting a reallocation as one
+    pub allocations: u64,
+    /// The number of deallocations, counting a reallocation as one
+    pub deallocations: u64,
+    /// The number of bytes allocated
+    pub bytes_allocated: u64,
+    /// The number of bytes freed
+    pub bytes_freed: u64,
+    /// The highest number of bytes that were live at once on top of
+    /// what was live when the span started
+    pub peak_bytes: u64,
+}
+
+impl AllocStats {
+    /// Adds up the stats of two occurrences of a span.
+    pub(crate) fn merge(&mut self, other: &AllocStats) {
+        self.allocations += other.allocations;
+        self.deallocations += other.deallocations;
+        self.bytes_allocated += other.bytes_allocated;
+        self.bytes_freed += other.bytes_freed;
+        self.peak_bytes = self.peak_bytes.max(other.peak_bytes);
+    }
+}
+
+/// The counters at the start of a span.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct AllocStart {
+    allocations: u64,
+    deallocations: u64,
+    bytes_allocated: u64,
+    bytes_freed: u64,
+    live: i64,
+    /// The peak before the span started, restored when it ends
+    outer_peak: i64,
+}
+
+/// Snapshots the counters of the calling thread, or returns `None`
+/// if `FlameAlloc` isn't the global allocator.
+pub(crate) fn start() -> Option<AllocStart> {
+    if !INSTALLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    COUNTERS.try_with(|counters| {
+        let live = counters.live.get();
+        let start = AllocStart {
+            allocations: counters.allocations.get(),
+            deallocations: counters.deallocations.get(),
+            bytes_allocated: counters.bytes_allocated.get(),
+            bytes_freed: counters.bytes_freed.get(),
+            live,
+            outer_peak: counters.peak.get(),
+        };
+        counters.peak.set(live);
+        start
+    }).ok()
+}
+
+/// Returns what the calling thread allocated since `start`.
+///
+/// Spans have to finish in the opposite order that they started in
+/// for their peaks to be right.
+pub(crate) fn finish(start: &AllocStart) -> AllocStats {
+    COUNTERS.try_with(|counters| {
+        let peak = counters.peak.get();
+        counters.peak.set(peak.max(start.outer_peak));
+        AllocStats {
+            allocations: counters.allocations.get() - start.allocations,
+            deallocations: counters.deallocations.get() - start.deallocations,
+            bytes_allocated: counters.bytes_allocated.get() - start.bytes_allocated,
+            bytes_freed: counters.bytes_freed.get() - start.bytes_freed,
+            peak_bytes: (peak - start.live).max(0) as u64,
+        }
+    }).unwrap_or_default()
+}
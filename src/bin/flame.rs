@@ -0,0 +1,298 @@
+//! Works with profiles that were saved with `flame::dump_json`.
+
+extern crate flame;
+extern crate serde_json;
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::process;
+
+use flame::{Filter, Span, Thread};
+
+const USAGE: &str = "usage: flame <command> [options]
+
+commands:
+    convert <profile> --format <format>    convert to html, svg, timeline, folded, chrome or speedscope
+    summary <profile>                      show the threads and their total time
+    top <profile> [-n N] [--by self|total|calls]
+                                           show the most expensive spans by name
+    diff <before> <after> [-n N]           compare the total time of every span name
+    filter <profile> [--thread T] [--from NS] [--to NS] [--min NS]
+                                           keep only some threads and spans
+    merge <profile>...                     combine several profiles into one
+
+Profiles are JSON files written by flame::dump_json, `-` reads standard input.
+convert, filter and merge write to standard output unless -o <file> is given.";
+
+/// Options that take a value.
+const VALUED: &[&str] = &["-o", "--format", "-n", "--by", "--thread", "--from", "--to", "--min"];
+
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Args, String> {
+        let mut parsed = Args { positional: vec![], options: HashMap::new() };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if VALUED.contains(&&arg[..]) {
+                let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+                parsed.options.insert(arg.clone(), value.clone());
+            } else if arg.starts_with('-') && arg != "-" {
+                return Err(format!("unknown option {}", arg));
+            } else {
+                parsed.positional.push(arg.clone());
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(|value| &value[..])
+    }
+
+    fn number(&self, name: &str) -> Result<Option<u64>, String> {
+        match self.option(name) {
+            Some(value) => value.parse().map(Some).map_err(|_| format!("{} must be a number", name)),
+            None => Ok(None),
+        }
+    }
+
+    fn single_input(&self) -> Result<Vec<Thread>, String> {
+        match self.positional.len() {
+            1 => load(&self.positional[0]),
+            _ => Err("expected exactly one profile".into()),
+        }
+    }
+
+    fn output(&self) -> Result<Box<dyn Write>, String> {
+        match self.option("-o") {
+            Some(path) => File::create(path)
+                .map(|file| Box::new(BufWriter::new(file)) as Box<dyn Write>)
+                .map_err(|e| format!("can't create {}: {}", path, e)),
+            None => Ok(Box::new(BufWriter::new(io::stdout()))),
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(message) = run(&args) {
+        eprintln!("flame: {}", message);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let command = match args.first() {
+        Some(command) if command != "-h" && command != "--help" => command,
+        _ => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+    };
+    let args = Args::parse(&args[1 ..])?;
+    match &command[..] {
+        "convert" => convert(&args),
+        "summary" => summary(&args),
+        "top" => top(&args),
+        "diff" => diff(&args),
+        "filter" => filter(&args),
+        "merge" => merge(&args),
+        _ => Err(format!("unknown command {}\n\n{}", command, USAGE)),
+    }
+}
+
+fn load(path: &str) -> Result<Vec<Thread>, String> {
+    let loaded = if path == "-" {
+        flame::load_json(io::stdin().lock())
+    } else {
+        let file = File::open(path).map_err(|e| format!("can't open {}: {}", path, e))?;
+        flame::load_json(BufReader::new(file))
+    };
+    loaded.map_err(|e| format!("can't read {}: {}", path, e))
+}
+
+fn write_error(e: io::Error) -> String {
+    format!("can't write the output: {}", e)
+}
+
+fn ms(ns: u64) -> String {
+    format!("{:.3}ms", ns as f64 / 1_000_000.0)
+}
+
+fn convert(args: &Args) -> Result<(), String> {
+    let threads = args.single_input()?;
+    let mut out = args.output()?;
+    match args.option("--format") {
+        Some("html") => flame::dump_html_threads(&mut out, &threads),
+        Some("svg") => flame::dump_svg_threads(&mut out, &threads, &flame::SvgOptions::new()),
+        Some("timeline") => flame::dump_timeline_custom(&mut out, &threads),
+        Some("folded") => flame::dump_folded(&mut out, &threads),
+        Some("chrome") => flame::dump_chrome(&mut out, &threads),
+        Some("speedscope") => flame::dump_speedscope(&mut out, &threads),
+        Some(format) => return Err(format!("unknown format {}", format)),
+        None => return Err("convert needs a --format".into()),
+    }.and_then(|()| out.flush()).map_err(write_error)
+}
+
+fn summary(args: &Args) -> Result<(), String> {
+    fn count(spans: &[Span]) -> u64 {
+        spans.iter().map(|span| span.weight + count(&span.children)).sum()
+    }
+
+    let threads = args.single_input()?;
+    let mut total = 0;
+    for thread in &threads {
+        let time: u64 = thread.spans.iter().map(Span::weighted_delta).sum();
+        total += time;
+        let name = thread.name.clone().unwrap_or_else(|| "<unnamed>".into());
+        let window = match (thread.spans.first(), thread.spans.last()) {
+            (Some(first), Some(last)) => format!("{} .. {}", ms(first.start_ns), ms(last.end_ns)),
+            _ => "empty".into(),
+        };
+        println!("thread {} {}: {} in {} spans ({} top level), {}",
+                 thread.id, name, ms(time), count(&thread.spans), thread.spans.len(), window);
+    }
+    println!("{} threads, {} in total", threads.len(), ms(total));
+    Ok(())
+}
+
+#[derive(Default, Clone, Copy)]
+struct Totals {
+    calls: u64,
+    total_ns: u64,
+    self_ns: u64,
+}
+
+/// Adds up the spans of every thread by name.
+fn totals(threads: &[Thread]) -> BTreeMap<String, Totals> {
+    fn add(span: &Span, totals: &mut BTreeMap<String, Totals>) {
+        let children: u64 = span.children.iter().map(Span::weighted_delta).sum();
+        let entry = totals.entry(span.name.to_string()).or_default();
+        entry.calls += span.weight;
+        entry.total_ns += span.weighted_delta();
+        entry.self_ns += span.weighted_delta().saturating_sub(children);
+        for child in &span.children {
+            add(child, totals);
+        }
+    }
+
+    let mut totals = BTreeMap::new();
+    for thread in threads {
+        for span in &thread.spans {
+            add(span, &mut totals);
+        }
+    }
+    totals
+}
+
+fn top(args: &Args) -> Result<(), String> {
+    let threads = args.single_input()?;
+    let n = args.number("-n")?.unwrap_or(20) as usize;
+    let mut totals: Vec<_> = totals(&threads).into_iter().collect();
+    match args.option("--by").unwrap_or("self") {
+        "self" => totals.sort_by_key(|&(_, totals)| ::std::cmp::Reverse(totals.self_ns)),
+        "total" => totals.sort_by_key(|&(_, totals)| ::std::cmp::Reverse(totals.total_ns)),
+        "calls" => totals.sort_by_key(|&(_, totals)| ::std::cmp::Reverse(totals.calls)),
+        by => return Err(format!("can't sort by {}, expected self, total or calls", by)),
+    }
+
+    println!("{:>14} {:>14} {:>10}  name", "self", "total", "calls");
+    for (name, totals) in totals.into_iter().take(n) {
+        println!("{:>14} {:>14} {:>10}  {}", ms(totals.self_ns), ms(totals.total_ns), totals.calls, name);
+    }
+    Ok(())
+}
+
+fn diff(args: &Args) -> Result<(), String> {
+    if args.positional.len() != 2 {
+        return Err("diff needs two profiles".into());
+    }
+    let before = totals(&load(&args.positional[0])?);
+    let after = totals(&load(&args.positional[1])?);
+    let n = args.number("-n")?.unwrap_or(20) as usize;
+
+    let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+    names.sort();
+    names.dedup();
+    let mut changes: Vec<(&String, Totals, Totals, i64)> = names.into_iter().map(|name| {
+        let before = before.get(name).cloned().unwrap_or_default();
+        let after = after.get(name).cloned().unwrap_or_default();
+        (name, before, after, after.total_ns as i64 - before.total_ns as i64)
+    }).collect();
+    changes.sort_by_key(|&(_, _, _, change)| ::std::cmp::Reverse(change.abs()));
+
+    println!("{:>14} {:>14} {:>15} {:>9}  name", "before", "after", "change", "");
+    for (name, before, after, change) in changes.into_iter().take(n) {
+        let percent = if before.total_ns == 0 {
+            "new".to_owned()
+        } else {
+            format!("{:+.1}%", 100.0 * change as f64 / before.total_ns as f64)
+        };
+        let sign = if change < 0 { "-" } else { "+" };
+        println!("{:>14} {:>14} {:>15} {:>9}  {} ({} -> {} calls)",
+                 ms(before.total_ns), ms(after.total_ns), format!("{}{}", sign, ms(change.unsigned_abs())),
+                 percent, name, before.calls, after.calls);
+    }
+    Ok(())
+}
+
+fn write_json(args: &Args, threads: &[Thread]) -> Result<(), String> {
+    let mut out = args.output()?;
+    serde_json::to_writer_pretty(&mut out, threads).map_err(io::Error::from)
+        .and_then(|()| writeln!(out))
+        .and_then(|()| out.flush())
+        .map_err(write_error)
+}
+
+fn filter(args: &Args) -> Result<(), String> {
+    let threads = args.single_input()?;
+    let mut filter = Filter::new();
+    if let Some(thread) = args.option("--thread") {
+        filter = filter.thread(thread);
+    }
+    let from = args.number("--from")?;
+    let to = args.number("--to")?;
+    if from.is_some() || to.is_some() {
+        filter = filter.window(from.unwrap_or(0), to.unwrap_or(u64::MAX));
+    }
+    if let Some(min) = args.number("--min")? {
+        filter = filter.min_duration(min);
+    }
+    write_json(args, &filter.threads(&threads))
+}
+
+fn merge(args: &Args) -> Result<(), String> {
+    if args.positional.is_empty() {
+        return Err("merge needs at least one profile".into());
+    }
+    // Threads with the same id and name are combined into one.
+    let mut merged: Vec<Thread> = vec![];
+    for path in &args.positional {
+        for thread in load(path)? {
+            match merged.iter_mut().find(|merged| merged.id == thread.id && merged.name == thread.name) {
+                Some(merged) => {
+                    merged.spans.extend(thread.spans);
+                    merged.async_spans.extend(thread.async_spans);
+                    merged.notes.extend(thread.notes);
+                    merged.frame_marks.extend(thread.frame_marks);
+                    merged.marks.extend(thread.marks);
+                }
+                None => merged.push(thread),
+            }
+        }
+    }
+    for thread in &mut merged {
+        thread.spans.sort_by_key(|span| span.start_ns);
+        thread.async_spans.sort_by_key(|span| span.start_ns);
+        thread.notes.sort_by_key(|note| note.instant);
+        thread.frame_marks.sort();
+        thread.marks.sort_by_key(|mark| mark.instant);
+    }
+    write_json(args, &merged)
+}
@@ -0,0 +1,193 @@
+//! Clock sources for timestamps, and the CPU time of spans.
+//!
+//! Every clock counts nanoseconds from the same point early in the
+//! process, so switching clocks with `flame::set_clock` keeps the
+//! timestamps of existing sessions roughly in line.
+
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::time::Instant;
+
+lazy_static! {
+    static ref ANCHOR: Instant = Instant::now();
+}
+
+/// The clock chosen with `set_clock`, or null for `InstantClock`.
+/// Clocks are leaked so that reading one never takes a lock.
+static CLOCK: AtomicPtr<Box<dyn Clock>> = AtomicPtr::new(ptr::null_mut());
+static CPU_TIME: AtomicBool = AtomicBool::new(false);
+
+/// A source of monotonic timestamps.
+pub trait Clock: Send + Sync {
+    /// Returns the nanoseconds that passed since the clocks' common
+    /// starting point, see `anchor_ns`.
+    fn now_ns(&self) -> u64;
+}
+
+/// Measures time with `std::time::Instant`.  This is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InstantClock;
+
+impl Clock for InstantClock {
+    fn now_ns(&self) -> u64 {
+        anchor_ns()
+    }
+}
+
+/// Returns the nanoseconds since the clocks' common starting point,
+/// as measured by `Instant`.  Other clocks align themselves to it.
+pub fn anchor_ns() -> u64 {
+    let elapsed = ANCHOR.elapsed();
+    elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos())
+}
+
+/// Measures time with `CLOCK_MONOTONIC_RAW`, which isn't slewed by NTP.
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicRawClock {
+    origin: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl MonotonicRawClock {
+    pub fn new() -> MonotonicRawClock {
+        let anchor = anchor_ns();
+        MonotonicRawClock { origin: monotonic_raw_ns().saturating_sub(anchor) }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Default for MonotonicRawClock {
+    fn default() -> MonotonicRawClock {
+        MonotonicRawClock::new()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Clock for MonotonicRawClock {
+    fn now_ns(&self) -> u64 {
+        monotonic_raw_ns().saturating_sub(self.origin)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn monotonic_raw_ns() -> u64 {
+    let mut ts = ::libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+        ::libc::clock_gettime(::libc::CLOCK_MONOTONIC_RAW, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Measures time with the `rdtsc` instruction, which is much cheaper
+/// than asking the operating system.
+///
+/// The tick rate is measured against `Instant` when the clock is
+/// created, which takes about 10ms.
+#[cfg(target_arch = "x86_64")]
+#[derive(Debug, Clone, Copy)]
+pub struct TscClock {
+    origin_ticks: u64,
+    origin_ns: u64,
+    /// Nanoseconds per tick as a 32.32 fixed point number
+    scale: u64,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl TscClock {
+    /// Calibrates a new TSC clock.
+    ///
+    /// Returns `None` if the CPU doesn't have an invariant TSC, in
+    /// which case the tick rate can change with the CPU frequency.
+    pub fn calibrate() -> Option<TscClock> {
+        if !invariant_tsc() {
+            return None;
+        }
+
+        let start_ns = anchor_ns();
+        let start_ticks = rdtsc();
+        let mut end_ns = start_ns;
+        while end_ns - start_ns < 10_000_000 {
+            end_ns = anchor_ns();
+        }
+        let end_ticks = rdtsc();
+
+        let ticks = end_ticks.checked_sub(start_ticks).filter(|&ticks| ticks > 0)?;
+        let scale = ((u128::from(end_ns - start_ns) << 32) / u128::from(ticks)) as u64;
+        Some(TscClock { origin_ticks: end_ticks, origin_ns: end_ns, scale })
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Clock for TscClock {
+    fn now_ns(&self) -> u64 {
+        let ticks = rdtsc().saturating_sub(self.origin_ticks);
+        self.origin_ns + ((u128::from(ticks) * u128::from(self.scale)) >> 32) as u64
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[allow(unused_unsafe)]
+fn rdtsc() -> u64 {
+    unsafe { ::std::arch::x86_64::_rdtsc() }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[allow(unused_unsafe)]
+fn invariant_tsc() -> bool {
+    use std::arch::x86_64::__cpuid;
+
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended < 0x8000_0007 {
+        return false;
+    }
+    unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+/// Makes every session take its timestamps from `clock` from now on.
+///
+/// Spans that are open while the clock changes may be off by a few
+/// microseconds.  The previous clock is leaked, so this is meant to be
+/// called once at startup rather than in a loop.
+pub fn set_clock<C: Clock + 'static>(clock: C) {
+    let clock: Box<Box<dyn Clock>> = Box::new(Box::new(clock));
+    CLOCK.store(Box::into_raw(clock), Ordering::Release);
+}
+
+/// Returns the current time according to the chosen clock.
+pub(crate) fn now_ns() -> u64 {
+    let clock = CLOCK.load(Ordering::Acquire);
+    if clock.is_null() {
+        anchor_ns()
+    } else {
+        unsafe { (*clock).now_ns() }
+    }
+}
+
+/// Turns recording the thread's CPU time at the start and end of every
+/// span on or off, see `Span::cpu_ns`.
+///
+/// This reads `CLOCK_THREAD_CPUTIME_ID` twice per span and is only
+/// supported on Linux, elsewhere `cpu_ns` stays `None`.
+pub fn set_cpu_time(enabled: bool) {
+    CPU_TIME.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns the CPU time of the calling thread, or `None` if CPU time
+/// isn't recorded.
+#[cfg(target_os = "linux")]
+pub(crate) fn thread_cpu_ns() -> Option<u64> {
+    if !CPU_TIME.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mut ts = ::libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    if unsafe { ::libc::clock_gettime(::libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) } != 0 {
+        return None;
+    }
+    Some(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn thread_cpu_ns() -> Option<u64> {
+    None
+}
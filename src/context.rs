@@ -0,0 +1,120 @@
+//! Carries the session and the running span over to other threads.
+
+use std::thread::{self, JoinHandle, ScopedJoinHandle};
+
+use super::{Parent, ns_since_epoch};
+use session::{self, Session, with_session};
+
+/// The session and running span of a thread, to be attached to
+/// another thread.
+///
+/// `flame::spawn` and `flame::scope` do this for you, `Context` is for
+/// thread pools and other places where the threads aren't started
+/// through flame:
+///
+/// ```
+/// let context = flame::Context::current();
+/// std::thread::spawn(move || {
+///     context.attach(|| {
+///         let _guard = flame::start_guard("work");
+///     });
+/// }).join().unwrap();
+/// ```
+#[derive(Clone)]
+pub struct Context {
+    session: Session,
+    parent: Parent,
+}
+
+impl Context {
+    /// Captures the current session of the calling thread and its
+    /// innermost running span.
+    pub fn current() -> Context {
+        session::with_current(|session| {
+            let parent = session.with_library(|library| {
+                let collector = &mut library.current;
+                let running = collector.open_ids().last();
+                let (span, span_start_ns) = match running {
+                    Some(id) => {
+                        let event = collector.event_mut(id);
+                        (Some(event.name.clone()), Some(event.start_ns))
+                    }
+                    None => (None, None),
+                };
+                Parent {
+                    thread_id: library.id,
+                    thread_name: library.name.clone(),
+                    span,
+                    span_start_ns,
+                    instant: ns_since_epoch(library.epoch),
+                }
+            });
+            Context { session: session.clone(), parent }
+        })
+    }
+
+    /// Runs `f` on the calling thread, recording into the captured
+    /// session with the captured span as the parent of the thread.
+    ///
+    /// Everything the thread recorded is committed when `f` returns,
+    /// see `commit_thread`.
+    pub fn attach<F, R>(&self, f: F) -> R where F: FnOnce() -> R {
+        with_session(&self.session, || {
+            let parent = self.parent.clone();
+            self.session.with_library(|library| library.current.parent = Some(parent));
+            let result = f();
+            self.session.commit_thread();
+            result
+        })
+    }
+}
+
+/// Spawns a thread with the given name that records into the current
+/// session, with the running span as its parent.
+///
+/// # Panics
+///
+/// Panics if the thread can't be spawned, like `std::thread::spawn`.
+pub fn spawn<N, F, T>(name: N, f: F) -> JoinHandle<T>
+where N: Into<String>, F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    let context = Context::current();
+    thread::Builder::new()
+        .name(name.into())
+        .spawn(move || context.attach(f))
+        .expect("failed to spawn thread")
+}
+
+/// A scope to spawn threads that can borrow from the calling thread,
+/// see `flame::scope`.
+pub struct Scope<'scope, 'env: 'scope> {
+    scope: &'scope thread::Scope<'scope, 'env>,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Like `flame::spawn`, for a thread that is joined at the end of
+    /// the scope.
+    pub fn spawn<N, F, T>(&self, name: N, f: F) -> ScopedJoinHandle<'scope, T>
+    where N: Into<String>, F: FnOnce() -> T + Send + 'scope, T: Send + 'scope {
+        let context = Context::current();
+        thread::Builder::new()
+            .name(name.into())
+            .spawn_scoped(self.scope, move || context.attach(f))
+            .expect("failed to spawn thread")
+    }
+}
+
+/// Like `std::thread::scope`, with threads that record into the
+/// current session.
+///
+/// ```
+/// let mut totals = [0; 2];
+/// flame::scope(|scope| {
+///     for (i, total) in totals.iter_mut().enumerate() {
+///         scope.spawn(format!("worker {}", i), move || *total = i + 1);
+///     }
+/// });
+/// assert_eq!(totals, [1, 2]);
+/// ```
+pub fn scope<'env, F, T>(f: F) -> T where F: for<'scope> FnOnce(&Scope<'scope, 'env>) -> T {
+    thread::scope(|scope| f(&Scope { scope }))
+}
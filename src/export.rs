@@ -0,0 +1,451 @@
+//! Exporters for the formats of other profiling tools.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+
+#[cfg(feature = "json")]
+use super::Note;
+#[cfg(feature = "json")]
+use marks::Mark;
+use super::{Span, Thread};
+
+/// What the flamegraph exporters size spans by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// The time spent in a span, in nanoseconds
+    Time,
+    /// The number of allocations made in a span, see `Span::alloc`
+    Allocations,
+    /// The number of bytes allocated in a span, see `Span::alloc`
+    Bytes,
+}
+
+impl Metric {
+    /// The value of `span` including its children, scaled by its weight.
+    pub(crate) fn total(self, span: &Span) -> u64 {
+        let value = match (self, span.alloc) {
+            (Metric::Time, _) => span.delta,
+            (Metric::Allocations, Some(alloc)) => alloc.allocations,
+            (Metric::Bytes, Some(alloc)) => alloc.bytes_allocated,
+            (_, None) => 0,
+        };
+        value.saturating_mul(span.weight)
+    }
+
+    /// The value of `span` without its children.
+    pub(crate) fn own(self, span: &Span) -> u64 {
+        let children: u64 = span.children.iter().map(|child| self.total(child)).sum();
+        self.total(span).saturating_sub(children)
+    }
+}
+
+/// The async spans of `thread`, by lane.
+pub(crate) fn lanes(thread: &Thread) -> BTreeMap<u32, Vec<&Span>> {
+    let mut lanes = BTreeMap::new();
+    for span in &thread.async_spans {
+        lanes.entry(span.lane.unwrap_or(0)).or_insert_with(Vec::new).push(span);
+    }
+    lanes
+}
+
+/// A span named `async lane N` for each async lane of `thread`, with the
+/// spans of the lane as its children.  Flamegraphs show these next to the
+/// spans of the stack.
+pub(crate) fn lane_roots(thread: &Thread) -> Vec<Span> {
+    lanes(thread).into_iter().map(|(lane, spans)| {
+        let children: Vec<Span> = spans.into_iter().cloned().map(|mut span| {
+            span.depth = 1;
+            span
+        }).collect();
+        let delta = children.iter().map(Span::weighted_delta).sum();
+        Span {
+            name: format!("async lane {}", lane).into(),
+            start_ns: children.iter().map(|span| span.start_ns).min().unwrap_or(0),
+            end_ns: children.iter().map(|span| span.end_ns).max().unwrap_or(0),
+            delta,
+            raw_delta: delta,
+            is_open: children.iter().any(|span| span.is_open),
+            panicked: false,
+            depth: 0,
+            weight: 1,
+            cpu_ns: None,
+            alloc: None,
+            children,
+            notes: vec![],
+            location: None,
+            lane: Some(lane),
+            attributes: vec![],
+            collapsable: false,
+            _priv: (),
+        }
+    }).collect()
+}
+
+/// Writes the spans in the folded stack format of `flamegraph.pl`
+/// and inferno: one line per distinct stack with the time that was
+/// spent in it (and not in its children) in nanoseconds.  Async spans
+/// are on stacks of their own, below `async lane N`.
+pub fn dump_folded<W: Write>(out: W, threads: &[Thread]) -> io::Result<()> {
+    dump_folded_with(out, threads, Metric::Time)
+}
+
+/// Like `dump_folded`, with the stacks weighted by `metric`, so that
+/// `Metric::Bytes` gives a memory flamegraph.
+pub fn dump_folded_with<W: Write>(mut out: W, threads: &[Thread], metric: Metric) -> io::Result<()> {
+    fn fold(span: &Span, prefix: &str, metric: Metric, stacks: &mut BTreeMap<String, u64>) {
+        let name = span.name.replace(';', ":").replace('\n', " ");
+        let stack = if prefix.is_empty() { name } else { format!("{};{}", prefix, name) };
+        *stacks.entry(stack.clone()).or_insert(0) += metric.own(span);
+        for child in &span.children {
+            fold(child, &stack, metric, stacks);
+        }
+    }
+
+    let mut stacks = BTreeMap::new();
+    for thread in threads {
+        for span in thread.spans.iter().chain(&lane_roots(thread)) {
+            fold(span, "", metric, &mut stacks);
+        }
+    }
+    for (stack, ns) in stacks {
+        if ns > 0 {
+            writeln!(out, "{} {}", stack, ns)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads threads that were written by `dump_json`.
+#[cfg(feature = "json")]
+pub fn load_json<R: Read>(input: R) -> io::Result<Vec<Thread>> {
+    ::serde_json::from_reader(input).map_err(io::Error::from)
+}
+
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct ChromeEvent<'a> {
+    name: &'a str,
+    ph: &'static str,
+    /// Microseconds
+    ts: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    s: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cat: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    pid: u32,
+    tid: usize,
+    args: BTreeMap<&'static str, ::serde_json::Value>,
+}
+
+/// Writes the spans in the Trace Event format of `chrome://tracing`
+/// and Perfetto.  Notes, frame marks and marks become instant events
+/// and every async lane becomes a track of async events.
+#[cfg(feature = "json")]
+pub fn dump_chrome<W: Write>(out: W, threads: &[Thread]) -> io::Result<()> {
+    dump_chrome_with_marks(out, threads, &[])
+}
+
+/// Like `dump_chrome`, with a global instant event for every mark in
+/// `marks` as well.
+#[cfg(feature = "json")]
+pub fn dump_chrome_with_marks<W: Write>(out: W, threads: &[Thread], marks: &[Mark]) -> io::Result<()> {
+    use serde_json::Value;
+
+    fn instant<'a>(note: &'a Note, tid: usize) -> ChromeEvent<'a> {
+        let mut args = BTreeMap::new();
+        if let Some(ref description) = note.description {
+            args.insert("description", Value::from(&description[..]));
+        }
+        ChromeEvent {
+            name: &note.name,
+            ph: "i",
+            ts: note.instant as f64 / 1000.0,
+            dur: None,
+            s: Some("t"),
+            cat: None,
+            id: None,
+            pid: 1,
+            tid,
+            args,
+        }
+    }
+
+    fn add<'a>(span: &'a Span, tid: usize, events: &mut Vec<ChromeEvent<'a>>) {
+        let mut args = BTreeMap::new();
+        if span.weight > 1 {
+            args.insert("weight", Value::from(span.weight));
+        }
+        if let Some(ref location) = span.location {
+            args.insert("location", Value::from(location.to_string()));
+        }
+        if let Some(cpu_ns) = span.cpu_ns {
+            args.insert("cpu_ns", Value::from(cpu_ns));
+        }
+        if let Some(ref alloc) = span.alloc {
+            args.insert("bytes_allocated", Value::from(alloc.bytes_allocated));
+            args.insert("allocations", Value::from(alloc.allocations));
+        }
+        events.push(ChromeEvent {
+            name: &span.name,
+            ph: "X",
+            ts: span.start_ns as f64 / 1000.0,
+            dur: Some(span.end_ns.saturating_sub(span.start_ns) as f64 / 1000.0),
+            s: None,
+            cat: None,
+            id: None,
+            pid: 1,
+            tid,
+            args,
+        });
+        for note in &span.notes {
+            events.push(instant(note, tid));
+        }
+        for child in &span.children {
+            add(child, tid, events);
+        }
+    }
+
+    let mut events = vec![];
+    for thread in threads {
+        if let Some(ref name) = thread.name {
+            let mut args = BTreeMap::new();
+            args.insert("name", Value::from(&name[..]));
+            events.push(ChromeEvent {
+                name: "thread_name",
+                ph: "M",
+                ts: 0.0,
+                dur: None,
+                s: None,
+                cat: None,
+                id: None,
+                pid: 1,
+                tid: thread.id,
+                args,
+            });
+        }
+        for span in &thread.spans {
+            add(span, thread.id, &mut events);
+        }
+        for note in &thread.notes {
+            events.push(instant(note, thread.id));
+        }
+        for &ns in &thread.frame_marks {
+            events.push(ChromeEvent {
+                name: "frame",
+                ph: "i",
+                ts: ns as f64 / 1000.0,
+                dur: None,
+                s: Some("t"),
+                cat: Some("frame"),
+                id: None,
+                pid: 1,
+                tid: thread.id,
+                args: BTreeMap::new(),
+            });
+        }
+        for span in &thread.async_spans {
+            let id = format!("{}.{}", thread.id, span.lane.unwrap_or(0));
+            for &(ph, ns) in &[("b", span.start_ns), ("e", span.end_ns)] {
+                events.push(ChromeEvent {
+                    name: &span.name,
+                    ph,
+                    ts: ns as f64 / 1000.0,
+                    dur: None,
+                    s: None,
+                    cat: Some("async"),
+                    id: Some(id.clone()),
+                    pid: 1,
+                    tid: thread.id,
+                    args: BTreeMap::new(),
+                });
+            }
+        }
+    }
+
+    for mark in threads.iter().flat_map(|thread| &thread.marks).chain(marks) {
+        events.push(ChromeEvent {
+            name: &mark.name,
+            ph: "i",
+            ts: mark.instant as f64 / 1000.0,
+            dur: None,
+            s: Some("g"),
+            cat: None,
+            id: None,
+            pid: 1,
+            tid: 0,
+            args: BTreeMap::new(),
+        });
+    }
+
+    let mut trace = BTreeMap::new();
+    trace.insert("traceEvents", events);
+    ::serde_json::to_writer(out, &trace).map_err(io::Error::from)
+}
+
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpeedscopeProfile {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    name: String,
+    unit: &'static str,
+    start_value: u64,
+    end_value: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    events: Option<Vec<SpeedscopeEvent>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    samples: Option<Vec<Vec<usize>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weights: Option<Vec<u64>>,
+}
+
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct SpeedscopeEvent {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    frame: usize,
+    at: u64,
+}
+
+/// Writes the spans in the file format of speedscope.app, with one
+/// evented profile per thread and one per async lane.  Threads with
+/// allocation stats also get a sampled profile of their allocations
+/// and one of their bytes.
+#[cfg(feature = "json")]
+pub fn dump_speedscope<W: Write>(out: W, threads: &[Thread]) -> io::Result<()> {
+    use std::collections::HashMap;
+    use serde_json::Value;
+
+    struct Frames<'a> {
+        names: Vec<&'a str>,
+        ids: HashMap<&'a str, usize>,
+    }
+
+    impl<'a> Frames<'a> {
+        fn get(&mut self, name: &'a str) -> usize {
+            if let Some(&frame) = self.ids.get(name) {
+                return frame;
+            }
+            self.names.push(name);
+            self.ids.insert(name, self.names.len() - 1);
+            self.names.len() - 1
+        }
+    }
+
+    // Children are clamped to their parent and every event to the one
+    // before it, speedscope rejects profiles that go back in time.
+    fn add<'a>(span: &'a Span, from: u64, to: u64, frames: &mut Frames<'a>,
+               events: &mut Vec<SpeedscopeEvent>, at: &mut u64) {
+        let frame = frames.get(&span.name);
+        let start = span.start_ns.max(from).max(*at).min(to);
+        let end = span.end_ns.min(to).max(start);
+        events.push(SpeedscopeEvent { kind: "O", frame, at: start });
+        *at = start;
+        for child in &span.children {
+            add(child, start, end, frames, events, at);
+        }
+        events.push(SpeedscopeEvent { kind: "C", frame, at: end });
+        *at = end;
+    }
+
+    fn sample<'a>(span: &'a Span, metric: Metric, stack: &mut Vec<usize>, frames: &mut Frames<'a>,
+                  samples: &mut Vec<Vec<usize>>, weights: &mut Vec<u64>) {
+        stack.push(frames.get(&span.name));
+        let own = metric.own(span);
+        if own > 0 {
+            samples.push(stack.clone());
+            weights.push(own);
+        }
+        for child in &span.children {
+            sample(child, metric, stack, frames, samples, weights);
+        }
+        stack.pop();
+    }
+
+    fn has_alloc(spans: &[Span]) -> bool {
+        spans.iter().any(|span| span.alloc.is_some() || has_alloc(&span.children))
+    }
+
+    let mut frames = Frames { names: vec![], ids: HashMap::new() };
+    let mut profiles = vec![];
+    for thread in threads {
+        let mut events = vec![];
+        let mut at = thread.spans.first().map(|span| span.start_ns).unwrap_or(0);
+        let start_value = at;
+        for span in &thread.spans {
+            add(span, 0, u64::MAX, &mut frames, &mut events, &mut at);
+        }
+        let name = match thread.name {
+            Some(ref name) => format!("{} ({})", name, thread.id),
+            None => format!("thread {}", thread.id),
+        };
+        profiles.push(SpeedscopeProfile {
+            kind: "evented",
+            name: name.clone(),
+            unit: "nanoseconds",
+            start_value,
+            end_value: at,
+            events: Some(events),
+            samples: None,
+            weights: None,
+        });
+        for (lane, spans) in lanes(thread) {
+            let mut events = vec![];
+            let mut at = spans[0].start_ns;
+            let start_value = at;
+            for span in spans {
+                add(span, 0, u64::MAX, &mut frames, &mut events, &mut at);
+            }
+            profiles.push(SpeedscopeProfile {
+                kind: "evented",
+                name: format!("{} async lane {}", name, lane),
+                unit: "nanoseconds",
+                start_value,
+                end_value: at,
+                events: Some(events),
+                samples: None,
+                weights: None,
+            });
+        }
+        if !has_alloc(&thread.spans) {
+            continue;
+        }
+        for &(metric, what, unit) in &[(Metric::Allocations, "allocations", "none"), (Metric::Bytes, "bytes", "bytes")] {
+            let (mut samples, mut weights) = (vec![], vec![]);
+            for span in &thread.spans {
+                sample(span, metric, &mut vec![], &mut frames, &mut samples, &mut weights);
+            }
+            profiles.push(SpeedscopeProfile {
+                kind: "sampled",
+                name: format!("{} {}", name, what),
+                unit,
+                start_value: 0,
+                end_value: weights.iter().sum(),
+                events: None,
+                samples: Some(samples),
+                weights: Some(weights),
+            });
+        }
+    }
+
+    let frames: Vec<Value> = frames.names.iter().map(|name| {
+        let mut frame = ::serde_json::Map::new();
+        frame.insert("name".into(), Value::from(*name));
+        Value::Object(frame)
+    }).collect();
+    let mut shared = ::serde_json::Map::new();
+    shared.insert("frames".into(), Value::from(frames));
+
+    let mut file = ::serde_json::Map::new();
+    file.insert("$schema".into(), Value::from("https://www.speedscope.app/file-format-schema.json"));
+    file.insert("exporter".into(), Value::from("flame"));
+    file.insert("shared".into(), Value::Object(shared));
+    file.insert("profiles".into(), ::serde_json::to_value(profiles).map_err(io::Error::from)?);
+    ::serde_json::to_writer(out, &file).map_err(io::Error::from)
+}
@@ -0,0 +1,88 @@
+use super::{Span, Thread};
+
+/// Selects the threads and spans that a report should show.
+///
+/// ```
+/// let filter = flame::Filter::new()
+///     .thread("main")
+///     .window(0, 1_000_000_000)
+///     .min_duration(1_000_000);
+/// let threads = filter.threads(&flame::threads());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    thread: Option<String>,
+    window: Option<(u64, u64)>,
+    min_duration: u64,
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+
+    /// Only keep the thread with this name or id.
+    pub fn thread<S: Into<String>>(mut self, thread: S) -> Filter {
+        self.thread = Some(thread.into());
+        self
+    }
+
+    /// Only keep spans that overlap the time between `from_ns` and
+    /// `to_ns`, relative to the epoch of the session.
+    pub fn window(mut self, from_ns: u64, to_ns: u64) -> Filter {
+        self.window = Some((from_ns, to_ns));
+        self
+    }
+
+    /// Drop spans (and everything inside of them) that took less than
+    /// `min_ns` nanoseconds.
+    pub fn min_duration(mut self, min_ns: u64) -> Filter {
+        self.min_duration = min_ns;
+        self
+    }
+
+    /// Whether `thread` passes the thread filter.
+    pub fn keeps_thread(&self, thread: &Thread) -> bool {
+        match self.thread {
+            Some(ref wanted) => thread.name.as_ref() == Some(wanted) || thread.id.to_string() == *wanted,
+            None => true,
+        }
+    }
+
+    /// Whether `span` itself passes the time filters.
+    pub fn keeps_span(&self, span: &Span) -> bool {
+        if span.delta < self.min_duration {
+            return false;
+        }
+        match self.window {
+            Some((from_ns, to_ns)) => span.end_ns >= from_ns && span.start_ns <= to_ns,
+            None => true,
+        }
+    }
+
+    /// Returns the spans that pass the filter along with their children
+    /// that do.
+    pub fn spans(&self, spans: &[Span]) -> Vec<Span> {
+        spans.iter().filter(|span| self.keeps_span(span)).map(|span| {
+            let children = self.spans(&span.children);
+            let mut span = span.clone();
+            span.children = children;
+            span
+        }).collect()
+    }
+
+    /// Returns the threads that pass the filter with their spans filtered,
+    /// and only the notes of the thread itself that are in the window.
+    pub fn threads(&self, threads: &[Thread]) -> Vec<Thread> {
+        threads.iter().filter(|thread| self.keeps_thread(thread)).map(|thread| {
+            let spans = self.spans(&thread.spans);
+            let mut thread = thread.clone();
+            thread.spans = spans;
+            thread.async_spans = self.spans(&thread.async_spans);
+            if let Some((from_ns, to_ns)) = self.window {
+                thread.notes.retain(|note| note.instant >= from_ns && note.instant <= to_ns);
+            }
+            thread
+        }).collect()
+    }
+}
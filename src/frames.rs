@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+
+use super::{Event, Library, Span, SnapshotOptions, convert_detached_events, convert_events_to_span, ns_since_epoch};
+
+/// The number of frames that are kept per thread unless
+/// `set_frame_history` says otherwise.
+pub const DEFAULT_FRAME_HISTORY: usize = 600;
+
+/// Everything that finished on one thread between two calls to `frame_mark`.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "json", derive(Serialize))]
+pub struct FrameProfile {
+    /// The number of the frame, counting from 0 on every thread
+    pub number: u64,
+    /// The thread that recorded the frame
+    pub thread_id: usize,
+    /// The time of the previous `frame_mark`, or of the first
+    /// span in the frame for the very first frame
+    pub start_ns: u64,
+    /// The time of the `frame_mark` that ended the frame
+    pub end_ns: u64,
+    /// The time that ellapsed between start_ns and end_ns
+    pub delta: u64,
+    /// The spans that finished during the frame, with the children that
+    /// finished during the frame as well.  The children of a span that is
+    /// still running are top level spans of the frame.
+    pub spans: Vec<Span>,
+    /// The spans started with `flame::begin` that finished during the frame
+    pub async_spans: Vec<Span>,
+}
+
+/// The frames of one thread.
+#[derive(Debug)]
+pub(crate) struct FrameHistory {
+    next_number: u64,
+    last_mark_ns: Option<u64>,
+    /// The events that were running at the last mark
+    open_at_mark: Vec<u32>,
+    /// The id of the first event that started after the last mark
+    next_id_at_mark: u32,
+    pub(crate) frames: VecDeque<FrameProfile>,
+}
+
+impl FrameHistory {
+    pub(crate) fn new() -> FrameHistory {
+        FrameHistory {
+            next_number: 0,
+            last_mark_ns: None,
+            open_at_mark: vec![],
+            next_id_at_mark: 0,
+            frames: VecDeque::new(),
+        }
+    }
+}
+
+/// Turns the events of `library` that finished since the last mark into
+/// a new frame and drops the oldest frames so that at most `history`
+/// are kept.
+pub(crate) fn mark_frame(library: &mut Library, history: usize) {
+    let now = ns_since_epoch(library.epoch);
+    let current = &mut library.current;
+    let frames = &mut library.frames;
+
+    let ended_in_frame = |event: &Event| match (event.end_ns, frames.last_mark_ns) {
+        (Some(end), Some(last_mark)) => end > last_mark,
+        (Some(_), None) => true,
+        (None, _) => false,
+    };
+    // Only the events that were running at the last mark or started
+    // after it can have finished since, the others are in earlier frames.
+    let first_new = frames.next_id_at_mark as usize;
+    let finished: Vec<&Event> = frames.open_at_mark.iter()
+        .map(|&id| &current.all[id as usize])
+        .chain(&current.all[first_new.min(current.all.len()) ..])
+        .filter(|event| ended_in_frame(event))
+        .collect();
+    let finished_detached = current.detached.iter().filter(|event| ended_in_frame(event));
+
+    let start_ns = frames.last_mark_ns
+        .or_else(|| finished.iter().map(|event| event.start_ns).min())
+        .unwrap_or(now);
+    frames.frames.push_back(FrameProfile {
+        number: frames.next_number,
+        thread_id: library.id,
+        start_ns,
+        end_ns: now,
+        delta: now.saturating_sub(start_ns),
+        spans: convert_events_to_span(finished.into_iter(), &SnapshotOptions::default(), now),
+        async_spans: convert_detached_events(finished_detached, &SnapshotOptions::default(), now),
+    });
+    frames.next_number += 1;
+    frames.last_mark_ns = Some(now);
+    frames.open_at_mark = current.open_ids().collect();
+    frames.next_id_at_mark = current.next_id;
+    current.frame_marks.push(now);
+
+    while frames.frames.len() > history {
+        frames.frames.pop_front();
+    }
+}
+
+/// Counts the frames by duration.
+///
+/// Returns the lower bound of every non-empty bucket of width
+/// `bucket_ns` along with the number of frames in it, shortest first.
+pub fn frame_histogram(frames: &[FrameProfile], bucket_ns: u64) -> Vec<(u64, usize)> {
+    assert!(bucket_ns > 0, "flame::frame_histogram called with an empty bucket");
+
+    let mut buckets: Vec<(u64, usize)> = vec![];
+    let mut deltas: Vec<u64> = frames.iter().map(|frame| frame.delta / bucket_ns * bucket_ns).collect();
+    deltas.sort_unstable();
+    for bucket in deltas {
+        match buckets.last_mut() {
+            Some(last) if last.0 == bucket => last.1 += 1,
+            _ => buckets.push((bucket, 1)),
+        }
+    }
+    buckets
+}
+
+/// Returns the `count` longest frames, longest first.
+pub fn slowest_frames(frames: &[FrameProfile], count: usize) -> Vec<&FrameProfile> {
+    let mut slowest: Vec<&FrameProfile> = frames.iter().collect();
+    slowest.sort_by_key(|frame| ::std::cmp::Reverse(frame.delta));
+    slowest.truncate(count);
+    slowest
+}
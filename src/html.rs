@@ -1,29 +1,382 @@
 use std::io::Write;
 use std::io::Result as IoResult;
-use super::{Span};
-
-pub fn dump_html_custom<W: Write>(mut out: W, spans: &[Span]) -> IoResult<()> {
-    fn dump_spans<W: Write>(out: &mut W, span: &Span) -> IoResult<()> {
-        writeln!(out, "{{")?;
-        writeln!(out, r#"name: {:?},"#, span.name)?;
-        writeln!(out, "value: {},", span.delta)?;
-        writeln!(out, "start: {},", span.start_ns)?;
-        writeln!(out, "end: {},", span.end_ns)?;
-        writeln!(out, "children: [")?;
-        for child in &span.children {
-            dump_spans(out, child)?;
-            writeln!(out, ",")?;
+use super::{Note, SnapshotOptions, Span, Thread};
+use export::lanes;
+use marks::Mark;
+use svg::Palette;
+use text::SortOrder;
+use timeline::escape;
+
+/// Controls the page that `dump_html_with` writes.
+#[derive(Debug, Clone)]
+pub struct HtmlOptions {
+    title: Option<String>,
+    css: String,
+    script: String,
+    palette: Palette,
+    sort: SortOrder,
+    include_open: bool,
+}
+
+impl Default for HtmlOptions {
+    fn default() -> HtmlOptions {
+        HtmlOptions {
+            title: None,
+            css: String::new(),
+            script: String::new(),
+            palette: Palette::Hot,
+            sort: SortOrder::Start,
+            include_open: false,
+        }
+    }
+}
+
+impl HtmlOptions {
+    pub fn new() -> HtmlOptions {
+        HtmlOptions::default()
+    }
+
+    /// The title of the page.
+    pub fn title<S: Into<String>>(mut self, title: S) -> HtmlOptions {
+        self.title = Some(title.into());
+        self
+    }
+
+    /// CSS that is added after the built in styles.  It is inserted
+    /// into the page as is.
+    pub fn css<S: Into<String>>(mut self, css: S) -> HtmlOptions {
+        self.css = css.into();
+        self
+    }
+
+    /// JavaScript that runs once the flamegraph is drawn.  It is
+    /// inserted into the page as is and can use the `flamegraph` chart,
+    /// the `threads` data and `render()`, which draws it again.
+    pub fn script<S: Into<String>>(mut self, script: S) -> HtmlOptions {
+        self.script = script.into();
+        self
+    }
+
+    pub fn palette(mut self, palette: Palette) -> HtmlOptions {
+        self.palette = palette;
+        self
+    }
+
+    /// The order of sibling frames.
+    pub fn sort(mut self, sort: SortOrder) -> HtmlOptions {
+        self.sort = sort;
+        self
+    }
+
+    /// Also draw the spans of the session that haven't ended yet, see
+    /// `SnapshotOptions::include_open`.
+    pub fn include_open(mut self, include_open: bool) -> HtmlOptions {
+        self.include_open = include_open;
+        self
+    }
+
+    pub(crate) fn snapshot(&self) -> SnapshotOptions {
+        SnapshotOptions::new().include_open(self.include_open)
+    }
+}
+
+/// Writes a flamegraph of `spans`.
+pub fn dump_html_custom<W: Write>(out: W, spans: &[Span]) -> IoResult<()> {
+    write_page(out, &[("spans".to_owned(), spans, &[])], &[], &HtmlOptions::default())
+}
+
+/// Writes a flamegraph of every thread along with a selector that
+/// switches between them and a view of all threads side by side.
+/// Every async lane of a thread is shown like a thread of its own, and
+/// the marks of `Thread::marks` are listed with the notes.
+pub fn dump_html_threads<W: Write>(out: W, threads: &[Thread]) -> IoResult<()> {
+    dump_html_threads_with(out, threads, &HtmlOptions::default())
+}
+
+/// Like `dump_html_threads`, with the given options.
+pub fn dump_html_threads_with<W: Write>(out: W, threads: &[Thread], options: &HtmlOptions) -> IoResult<()> {
+    let mut names = vec![];
+    let mut lane_spans = vec![];
+    for thread in threads {
+        let name = match thread.name {
+            Some(ref name) => format!("{} ({})", name, thread.id),
+            None => format!("thread {}", thread.id),
+        };
+        let lanes: Vec<(String, Vec<Span>)> = lanes(thread).into_iter()
+            .map(|(lane, spans)| (format!("{}, async lane {}", name, lane), spans.into_iter().cloned().collect()))
+            .collect();
+        names.push(name);
+        lane_spans.push(lanes);
+    }
+    let mut pages: Vec<(String, &[Span], &[Note])> = vec![];
+    for ((thread, name), lanes) in threads.iter().zip(names).zip(&lane_spans) {
+        pages.push((name, &thread.spans[..], &thread.notes[..]));
+        for (name, spans) in lanes {
+            pages.push((name.clone(), &spans[..], &[]));
+        }
+    }
+    let marks: Vec<&Mark> = threads.iter().flat_map(|thread| &thread.marks).collect();
+    write_page(out, &pages, &marks, options)
+}
+
+/// A thread, or an async lane, as the script of the page sees it.
+#[cfg_attr(feature = "json", derive(Serialize))]
+struct PageThread<'a> {
+    name: &'a str,
+    notes: Vec<PageNote<'a>>,
+    spans: Vec<PageSpan<'a>>,
+}
+
+/// A note, or a mark, as the script of the page sees it.
+#[cfg_attr(feature = "json", derive(Serialize))]
+struct PageNote<'a> {
+    name: &'a str,
+    description: Option<&'a str>,
+    instant: u64,
+    location: Option<String>,
+}
+
+impl<'a> PageNote<'a> {
+    fn new(note: &'a Note) -> PageNote<'a> {
+        PageNote {
+            name: &note.name,
+            description: note.description.as_ref().map(|description| &description[..]),
+            instant: note.instant,
+            location: note.location.as_ref().map(ToString::to_string),
+        }
+    }
+
+    fn mark(mark: &'a Mark) -> PageNote<'a> {
+        PageNote { name: &mark.name, description: None, instant: mark.instant, location: None }
+    }
+}
+
+/// A span as the script of the page sees it.  `time` and `bytes` are
+/// what the frames can be sized by, scaled up by the span's weight.
+#[cfg_attr(feature = "json", derive(Serialize))]
+struct PageSpan<'a> {
+    name: &'a str,
+    time: u64,
+    start: u64,
+    end: u64,
+    open: bool,
+    panicked: bool,
+    wall: u64,
+    cpu: Option<u64>,
+    bytes: u64,
+    allocations: Option<u64>,
+    location: Option<String>,
+    attributes: &'a [(String, String)],
+    notes: Vec<PageNote<'a>>,
+    children: Vec<PageSpan<'a>>,
+}
+
+impl<'a> PageSpan<'a> {
+    fn new(span: &'a Span) -> PageSpan<'a> {
+        PageSpan {
+            name: &span.name,
+            time: span.weighted_delta(),
+            start: span.start_ns,
+            end: span.end_ns,
+            open: span.is_open,
+            panicked: span.panicked,
+            wall: span.delta,
+            cpu: span.cpu_ns,
+            bytes: span.alloc.map_or(0, |alloc| alloc.bytes_allocated.saturating_mul(span.weight)),
+            allocations: span.alloc.map(|alloc| alloc.allocations.saturating_mul(span.weight)),
+            location: span.location.as_ref().map(ToString::to_string),
+            attributes: &span.attributes,
+            notes: span.notes.iter().map(PageNote::new).collect(),
+            children: span.children.iter().map(PageSpan::new).collect(),
+        }
+    }
+}
+
+/// Writes `value` as JSON that can't end the `<script>` it is in.
+#[cfg(feature = "json")]
+fn to_json<T: ::serde::Serialize>(value: &T) -> String {
+    script_safe(::serde_json::to_string(value).expect("the page data can always be serialized"))
+}
+
+/// Writes `value` as JSON that can't end the `<script>` it is in.
+#[cfg(not(feature = "json"))]
+fn to_json<T: ToJson + ?Sized>(value: &T) -> String {
+    let mut json = String::new();
+    value.to_json(&mut json);
+    script_safe(json)
+}
+
+/// Writes the page data like `serde_json` would, for builds without
+/// the `json` feature.
+#[cfg(not(feature = "json"))]
+trait ToJson {
+    fn to_json(&self, json: &mut String);
+}
+
+#[cfg(not(feature = "json"))]
+fn object(json: &mut String, fields: &[(&str, &dyn ToJson)]) {
+    json.push('{');
+    for (i, &(name, value)) in fields.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        name.to_json(json);
+        json.push(':');
+        value.to_json(json);
+    }
+    json.push('}');
+}
+
+#[cfg(not(feature = "json"))]
+impl ToJson for str {
+    fn to_json(&self, json: &mut String) {
+        json.push('"');
+        for c in self.chars() {
+            match c {
+                '"' => json.push_str("\\\""),
+                '\\' => json.push_str("\\\\"),
+                '\n' => json.push_str("\\n"),
+                '\r' => json.push_str("\\r"),
+                '\t' => json.push_str("\\t"),
+                c if c < ' ' => json.push_str(&format!("\\u{:04x}", c as u32)),
+                c => json.push(c),
+            }
+        }
+        json.push('"');
+    }
+}
+
+#[cfg(not(feature = "json"))]
+impl ToJson for String {
+    fn to_json(&self, json: &mut String) {
+        self[..].to_json(json);
+    }
+}
+
+#[cfg(not(feature = "json"))]
+impl ToJson for u64 {
+    fn to_json(&self, json: &mut String) {
+        json.push_str(&self.to_string());
+    }
+}
+
+#[cfg(not(feature = "json"))]
+impl ToJson for bool {
+    fn to_json(&self, json: &mut String) {
+        json.push_str(if *self { "true" } else { "false" });
+    }
+}
+
+#[cfg(not(feature = "json"))]
+impl<T: ToJson + ?Sized> ToJson for &T {
+    fn to_json(&self, json: &mut String) {
+        (**self).to_json(json);
+    }
+}
+
+#[cfg(not(feature = "json"))]
+impl<T: ToJson> ToJson for Option<T> {
+    fn to_json(&self, json: &mut String) {
+        match *self {
+            Some(ref value) => value.to_json(json),
+            None => json.push_str("null"),
+        }
+    }
+}
+
+#[cfg(not(feature = "json"))]
+impl<T: ToJson> ToJson for [T] {
+    fn to_json(&self, json: &mut String) {
+        json.push('[');
+        for (i, value) in self.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            value.to_json(json);
         }
-        writeln!(out, "],")?;
-        writeln!(out, "}}")?;
-        Ok(())
+        json.push(']');
     }
+}
+
+#[cfg(not(feature = "json"))]
+impl<T: ToJson> ToJson for Vec<T> {
+    fn to_json(&self, json: &mut String) {
+        self[..].to_json(json);
+    }
+}
+
+#[cfg(not(feature = "json"))]
+impl<A: ToJson, B: ToJson> ToJson for (A, B) {
+    fn to_json(&self, json: &mut String) {
+        json.push('[');
+        self.0.to_json(json);
+        json.push(',');
+        self.1.to_json(json);
+        json.push(']');
+    }
+}
+
+#[cfg(not(feature = "json"))]
+impl<'a> ToJson for PageThread<'a> {
+    fn to_json(&self, json: &mut String) {
+        object(json, &[("name", &self.name), ("notes", &self.notes), ("spans", &self.spans)]);
+    }
+}
+
+#[cfg(not(feature = "json"))]
+impl<'a> ToJson for PageNote<'a> {
+    fn to_json(&self, json: &mut String) {
+        object(json, &[("name", &self.name), ("description", &self.description), ("instant", &self.instant),
+                       ("location", &self.location)]);
+    }
+}
+
+#[cfg(not(feature = "json"))]
+impl<'a> ToJson for PageSpan<'a> {
+    fn to_json(&self, json: &mut String) {
+        object(json, &[("name", &self.name), ("time", &self.time), ("start", &self.start), ("end", &self.end),
+                       ("open", &self.open), ("panicked", &self.panicked), ("wall", &self.wall),
+                       ("cpu", &self.cpu), ("bytes", &self.bytes), ("allocations", &self.allocations),
+                       ("location", &self.location), ("attributes", &self.attributes),
+                       ("notes", &self.notes), ("children", &self.children)]);
+    }
+}
+
+/// Escapes the characters of a JSON string that mean something to the
+/// HTML parser, and the line separators that old browsers don't allow
+/// in JavaScript strings.
+fn script_safe(json: String) -> String {
+    json.replace('<', "\\u003c")
+        .replace('>', "\\u003e")
+        .replace('&', "\\u0026")
+        .replace('\u{2028}', "\\u2028")
+        .replace('\u{2029}', "\\u2029")
+}
+
+fn write_page<W: Write>(mut out: W, threads: &[(String, &[Span], &[Note])], marks: &[&Mark],
+                        options: &HtmlOptions) -> IoResult<()> {
+    let title = match options.title {
+        Some(ref title) => format!("<title>{}</title>", escape(title)),
+        None => String::new(),
+    };
+    let palette = match options.palette {
+        Palette::Hot => "hot",
+        Palette::Mem => "mem",
+        Palette::Io => "io",
+        Palette::Gray => "gray",
+    };
+    let sort = match options.sort {
+        SortOrder::Start => "start",
+        SortOrder::Time => "time",
+        SortOrder::Name => "name",
+    };
 
     write!(out, r#"
 <!doctype html>
 <html>
     <head>
         <meta charset="utf-8">
+        {}
         <style>
             html, body {{
                 width: 100%;
@@ -32,6 +385,16 @@ pub fn dump_html_custom<W: Write>(mut out: W, spans: &[Span]) -> IoResult<()> {
                 padding: 0;
             }}
             {}
+            .note-marker {{
+                fill: black;
+                opacity: 0.6;
+            }}
+            #notes li {{
+                cursor: pointer;
+                font-family: Verdana, sans-serif;
+                font-size: 12px;
+            }}
+            {}
         </style>
         <script>
             {}
@@ -40,15 +403,87 @@ pub fn dump_html_custom<W: Write>(mut out: W, spans: &[Span]) -> IoResult<()> {
         </script>
     </head>
     <body>
+        <select id="thread"></select>
+        <label><input type="checkbox" id="cpu-share"> Color by CPU share</label>
+        <label><input type="checkbox" id="by-bytes"> Size by bytes allocated</label>
+        <div id="graph"></div>
+        <ul id="notes"></ul>
         <script>
             var width = document.body.offsetWidth;
             var height = document.body.offsetHeight - 100;
-            var flamegraph =
-                d3.flameGraph()
+            var flamegraph = d3.flameGraph();
+            var spanColor = flamegraph.color();
+            var colorByCpu = false;
+            var cpuColor = d3.interpolateRgb('lightsteelblue', 'orangered');
+            var sizeBy = 'time';
+            var palette = '{}';
+            var sortBy = '{}';
+            // The same colors as the SVG flamegraphs of `dump_svg`.
+            function paletteColor(name) {{
+                var hash = 2166136261;
+                for (var i = 0; i < name.length; i++) {{
+                    hash = Math.imul(hash ^ (name.charCodeAt(i) & 0xff), 16777619) >>> 0;
+                }}
+                var v1 = (hash & 0xff) / 255, v2 = ((hash >>> 8) & 0xff) / 255, v3 = ((hash >>> 16) & 0xff) / 255;
+                var rgb;
+                if (palette === 'mem') {{
+                    rgb = [0, 190 + 50 * v2, 210 * v1];
+                }} else if (palette === 'io') {{
+                    rgb = [80 + 60 * v1, 80 + 60 * v1, 190 + 55 * v2];
+                }} else {{
+                    rgb = [150 + 80 * v1, 150 + 80 * v1, 150 + 80 * v1];
+                }}
+                return 'rgb(' + rgb.map(Math.floor).join(',') + ')';
+            }}
+            flamegraph
                   .width(width)
                   .height(height)
                   .tooltip(false)
+                  .label(function(d) {{
+                    var size = sizeBy === 'bytes' ? d.value + " bytes" : d.value / 1000000 + "ms";
+                    var label = d.name + " (" + d3.round(100 * d.dx, 3) + "%, " + size + ")";
+                    if (d.open) {{
+                        label += " still running";
+                    }}
+                    if (d.panicked) {{
+                        label += " panicked";
+                    }}
+                    if (d.cpu !== null && d.cpu !== undefined) {{
+                        label += ", cpu " + d.cpu / 1000000 + "ms";
+                    }}
+                    if (d.allocations !== null && d.allocations !== undefined) {{
+                        label += ", " + d.allocations + " allocations";
+                    }}
+                    if (d.attributes && d.attributes.length) {{
+                        label += " {{" + d.attributes.map(function(a) {{ return a[0] + "=" + a[1]; }}).join(", ") + "}}";
+                    }}
+                    return d.location ? label + " at " + d.location : label;
+                  }})
+                  .color(function(d) {{
+                    if (d.highlight) {{
+                        return spanColor(d);
+                    }}
+                    if (colorByCpu) {{
+                        if (d.cpu === null || d.cpu === undefined || !d.wall) {{
+                            return 'lightgray';
+                        }}
+                        return cpuColor(Math.min(1, d.cpu / d.wall));
+                    }}
+                    if (d.panicked) {{
+                        return 'crimson';
+                    }}
+                    if (d.open) {{
+                        return 'lightsteelblue';
+                    }}
+                    return palette === 'hot' ? spanColor(d) : paletteColor(d.name);
+                  }})
                   .sort(function(a, b){{
+                    if (sortBy === 'time') {{
+                        return b.value - a.value;
+                    }}
+                    if (sortBy === 'name') {{
+                        return a.name < b.name ? -1 : a.name > b.name ? 1 : 0;
+                    }}
                     if (a.start < b.start) {{
                         return -1;
                     }} else if (a.start > b.start) {{
@@ -57,22 +492,145 @@ pub fn dump_html_custom<W: Write>(mut out: W, spans: &[Span]) -> IoResult<()> {
                         return 0;
                     }}
                   }});
-            d3.select("body").datum({{ children: [
-"#, include_str!("../resources/flameGraph.css"), include_str!("../resources/d3.js"), include_str!("../resources/d3-tip.js"), include_str!("../resources/flameGraph.js"))?;
+            var threads = "#, title, include_str!("../resources/flameGraph.css"), options.css, include_str!("../resources/d3.js"),
+       include_str!("../resources/d3-tip.js"), include_str!("../resources/flameGraph.js"), palette, sort)?;
 
-    for span in spans {
-        dump_spans(&mut out, &span)?;
-        writeln!(out, ",")?;
-    }
+    let threads: Vec<PageThread> = threads.iter().map(|&(ref name, spans, notes)| PageThread {
+        name,
+        notes: notes.iter().map(PageNote::new).collect(),
+        spans: spans.iter().map(PageSpan::new).collect(),
+    }).collect();
+    let marks: Vec<PageNote> = marks.iter().map(|mark| PageNote::mark(mark)).collect();
+    writeln!(out, "{};", to_json(&threads))?;
+    writeln!(out, "            var marks = {};", to_json(&marks))?;
 
-    write!(out, r#"]}}).call(flamegraph);
+    write!(out, r#"            function sized(spans) {{
+                return spans.map(function(span) {{
+                    var copy = {{}};
+                    for (var key in span) {{
+                        copy[key] = span[key];
+                    }}
+                    copy.value = span[sizeBy];
+                    copy.children = sized(span.children);
+                    return copy;
+                }});
+            }}
+            // Every thread becomes one frame of the merged view.
+            function merged() {{
+                return threads.map(function(thread) {{
+                    var children = sized(thread.spans);
+                    return {{
+                        name: thread.name,
+                        start: thread.spans.length ? thread.spans[0].start : 0,
+                        value: children.reduce(function(sum, child) {{ return sum + child.value; }}, 0),
+                        children: children
+                    }};
+                }});
+            }}
+            var selector = d3.select('#thread');
+            if (threads.length > 1) {{
+                selector.append('option').attr('value', -1).text('All threads');
+            }} else {{
+                selector.style('display', 'none');
+            }}
+            threads.forEach(function(thread, index) {{
+                selector.append('option').attr('value', index).text(thread.name);
+            }});
+            function noteText(note) {{
+                var text = note.name;
+                if (note.description !== null) {{
+                    text += ': ' + note.description;
+                }}
+                text += ' at ' + note.instant / 1000000 + 'ms';
+                return note.location ? text + ' (' + note.location + ')' : text;
+            }}
+            // Notes are drawn as thin markers at their instant inside the
+            // frame of the span they were added to.
+            function drawMarkers() {{
+                d3.selectAll('#graph g.frame').each(function(d) {{
+                    var frame = d3.select(this);
+                    frame.selectAll('.note-marker').remove();
+                    var width = +frame.attr('width');
+                    if (!d.notes || !d.notes.length || width < 1 || d.end <= d.start) {{
+                        return;
+                    }}
+                    d.notes.forEach(function(note) {{
+                        var x = width * Math.min(1, Math.max(0, (note.instant - d.start) / (d.end - d.start)));
+                        frame.append('svg:rect')
+                            .attr('class', 'note-marker')
+                            .attr('x', Math.max(0, Math.min(width - 2, x - 1)))
+                            .attr('width', 2)
+                            .attr('height', frame.attr('height'))
+                            .append('svg:title')
+                            .text(noteText(note));
+                    }});
+                }});
+            }}
+            function listNotes() {{
+                var list = d3.select('#notes');
+                list.selectAll('li').remove();
+                var entries = [];
+                d3.selectAll('#graph g.frame').each(function(d) {{
+                    (d.notes || []).forEach(function(note) {{
+                        entries.push({{ note: note, node: d }});
+                    }});
+                }});
+                // Notes that were recorded outside of any span belong to their thread.
+                var index = +selector.property('value');
+                (index < 0 ? threads : [threads[index]]).forEach(function(thread) {{
+                    thread.notes.forEach(function(note) {{
+                        entries.push({{ note: note, node: null, thread: thread.name }});
+                    }});
+                }});
+                marks.forEach(function(mark) {{
+                    entries.push({{ note: mark, node: null, thread: 'every thread' }});
+                }});
+                entries.sort(function(a, b) {{ return a.note.instant - b.note.instant; }});
+                list.selectAll('li').data(entries).enter().append('li')
+                    .text(function(entry) {{ return noteText(entry.note) + ' in ' + (entry.node ? entry.node.name : entry.thread); }})
+                    .on('click', function(entry) {{
+                        if (entry.node) {{
+                            flamegraph.zoomTo(entry.node);
+                        }} else {{
+                            flamegraph.resetZoom();
+                        }}
+                        document.getElementById('graph').scrollIntoView();
+                    }});
+            }}
+            flamegraph.onClick(drawMarkers);
+            function render() {{
+                var index = +selector.property('value');
+                var graph = d3.select('#graph');
+                graph.selectAll('svg').remove();
+                graph.datum({{ children: index < 0 ? merged() : sized(threads[index].spans) }}).call(flamegraph);
+                drawMarkers();
+                listNotes();
+            }}
+            render();
+            selector.on('change', render);
+            d3.select('#by-bytes').on('change', function() {{
+                sizeBy = this.checked ? 'bytes' : 'time';
+                render();
+            }});
+            d3.select('#cpu-share').on('change', function() {{
+                colorByCpu = this.checked;
+                d3.selectAll(".d3-flame-graph rect:not(.note-marker)").attr("fill", flamegraph.color());
+            }});
+         </script>
+         <script>
+{}
          </script>
     </body>
-</html>"#)?;
+</html>"#, options.script)?;
 
     Ok(())
 }
 
 pub fn dump_html<W: Write>(out: W) -> IoResult<()> {
-    dump_html_custom(out, &::spans())
+    ::session::with_current(|session| session.dump_html(out))
+}
+
+/// Writes the flamegraph of the current session with the given options.
+pub fn dump_html_with<W: Write>(out: W, options: &HtmlOptions) -> IoResult<()> {
+    ::session::with_current(|session| session.dump_html_with(out, options))
 }
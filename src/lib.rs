@@ -44,6 +44,8 @@
 #[macro_use]
 extern crate lazy_static;
 extern crate thread_id;
+#[cfg(target_os = "linux")]
+extern crate libc;
 
 #[cfg(feature = "json")]
 #[macro_use]
@@ -52,26 +54,75 @@ extern crate serde_derive;
 extern crate serde;
 #[cfg(feature = "json")]
 extern crate serde_json;
-
+#[cfg(feature = "macros")]
+extern crate flame_macros;
+#[cfg(feature = "log")]
+extern crate log;
+#[cfg(feature = "tracing")]
+extern crate tracing_core;
+#[cfg(feature = "tracing")]
+extern crate tracing_subscriber;
+
+pub mod alloc;
+pub mod clock;
+mod context;
+mod export;
+mod filter;
+mod frames;
 mod html;
+#[cfg(feature = "log")]
+mod logger;
+mod marks;
+mod overhead;
+mod sampling;
+mod serve;
+mod session;
+mod stream;
+mod svg;
+mod text;
+mod timeline;
+#[cfg(feature = "tracing")]
+mod tracing_layer;
 
 use std::cell::{RefCell, Cell};
 use std::iter::Peekable;
 use std::borrow::Cow;
-use std::sync::Mutex;
-use std::time::{Duration, Instant};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::sync::{Arc, Mutex};
 use std::io::{Write, Error as IoError};
 
 pub type StrCow = Cow<'static, str>;
 
-lazy_static!(static ref ALL_THREADS: Mutex<Vec<(usize, Option<String>, PrivateFrame)>> = Mutex::new(Vec::new()););
-thread_local!(static LIBRARY: RefCell<Library> = RefCell::new(Library::new()));
-
+pub use alloc::AllocStats;
+pub use clock::{Clock, set_clock, set_cpu_time};
+pub use context::{Context, Scope, scope, spawn};
+pub use export::{Metric, dump_folded, dump_folded_with};
+#[cfg(feature = "json")]
+pub use export::{dump_chrome, dump_chrome_with_marks, dump_speedscope, load_json};
+pub use filter::Filter;
+pub use serve::serve;
+pub use session::{Session, with_session};
+pub use stream::{LiveEvent, Listener, stream_to, stop_streaming, receive};
+pub use marks::{MAX_MARKS, Mark, clear_marks, mark, marks};
+pub use frames::{FrameProfile, DEFAULT_FRAME_HISTORY, frame_histogram, slowest_frames};
+pub use overhead::{Overhead, calibrate, overhead, set_overhead, set_overhead_correction};
+#[cfg(feature = "log")]
+pub use logger::FlameLogger;
+#[cfg(feature = "tracing")]
+pub use tracing_layer::FlameLayer;
+use frames::FrameHistory;
+use sampling::Sampler;
+
+/// Everything that one thread recorded into one `Session`.
 #[derive(Debug)]
 struct Library {
+    id: usize,
     name: Option<String>,
     current: PrivateFrame,
-    epoch: Instant,
+    frames: FrameHistory,
+    epoch: u64,
 }
 
 #[derive(Debug)]
@@ -79,6 +130,54 @@ struct PrivateFrame {
     next_id: u32,
     all: Vec<Event>,
     id_stack: Vec<u32>,
+    /// Notes that were recorded while no span was running
+    notes: Vec<Note>,
+    /// Where the recording thread was started from, see `Context`
+    parent: Option<Parent>,
+    /// Events started with `begin`, which aren't on the stack
+    detached: Vec<Event>,
+    next_detached_id: u32,
+    /// The times of the calls to `frame_mark`
+    frame_marks: Vec<u64>,
+}
+
+impl PrivateFrame {
+    fn new() -> PrivateFrame {
+        PrivateFrame {
+            next_id: 0,
+            all: vec![],
+            id_stack: vec![],
+            notes: vec![],
+            parent: None,
+            detached: vec![],
+            next_detached_id: 0,
+            frame_marks: vec![],
+        }
+    }
+
+    /// Whether nothing at all was recorded.
+    fn is_empty(&self) -> bool {
+        self.all.is_empty() && self.notes.is_empty() && self.detached.is_empty() && self.frame_marks.is_empty()
+    }
+
+    fn event_mut(&mut self, id: u32) -> &mut Event {
+        &mut self.all[id as usize]
+    }
+
+    /// The ids of the running spans, outermost first.  Spans that were
+    /// skipped by sampling are never on the stack.
+    fn open_ids<'a>(&'a self) -> impl DoubleEndedIterator<Item = u32> + 'a {
+        self.id_stack.iter().cloned()
+    }
+}
+
+#[derive(Debug)]
+struct CommittedFrame {
+    thread_id: usize,
+    thread_name: Option<String>,
+    /// The time of the commit, open spans are closed at this instant
+    committed_ns: u64,
+    frame: PrivateFrame,
 }
 
 #[derive(Debug)]
@@ -91,6 +190,14 @@ struct Event {
     end_ns: Option<u64>,
     delta: Option<u64>,
     notes: Vec<Note>,
+    location: Option<Location>,
+    panicked: bool,
+    weight: u64,
+    cpu_start_ns: Option<u64>,
+    cpu_ns: Option<u64>,
+    alloc_start: Option<alloc::AllocStart>,
+    alloc: Option<AllocStats>,
+    attributes: Vec<(String, String)>,
 }
 
 /// A named timespan.
@@ -103,7 +210,7 @@ struct Event {
 /// * A list of children (also called sub-spans)
 /// * A list of notes
 #[derive(Debug, Clone)]
-#[cfg_attr(feature = "json", derive(Serialize))]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
 pub struct Span {
     /// The name of the span
     pub name: StrCow,
@@ -112,22 +219,73 @@ pub struct Span {
     /// The timestamp of the end of the span
     pub end_ns: u64,
     /// The time that ellapsed between start_ns and end_ns
+    ///
+    /// If the overhead correction is turned on (see `set_overhead_correction`)
+    /// this has the estimated cost of recording everything inside of the span
+    /// subtracted.
     pub delta: u64,
+    /// The time that ellapsed between start_ns and end_ns, without
+    /// the overhead correction
+    #[cfg_attr(feature = "json", serde(default))]
+    pub raw_delta: u64,
+    /// Whether the span was still running when the snapshot was taken.
+    ///
+    /// Open spans are only reported when `SnapshotOptions::include_open`
+    /// is set, and their `end_ns` is the time of the snapshot.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub is_open: bool,
+    /// Whether the span was still running when its thread panicked.
+    ///
+    /// Only set when the panic hook from `install_panic_hook` is
+    /// installed, the span then ends at the time of the panic.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub panicked: bool,
     /// How deep this span is in the tree
     pub depth: u16,
+    /// How many occurrences of this span the recorded one stands for.
+    ///
+    /// This is 1 unless the span (or one of its parents) was sampled, see
+    /// `flame::sample`.  Aggregates should use `weighted_delta`.
+    #[cfg_attr(feature = "json", serde(default = "one"))]
+    pub weight: u64,
+    /// The CPU time that the thread spent inside of the span.
+    ///
+    /// Only recorded when `set_cpu_time(true)` was called before the span
+    /// started and the platform supports it, and `None` for open spans.
+    /// A span that waits on a lock or on I/O has a `cpu_ns` well below its
+    /// `delta`.
+    pub cpu_ns: Option<u64>,
+    /// What the thread allocated while the span was running.
+    ///
+    /// Only recorded when `flame::alloc::FlameAlloc` is the global
+    /// allocator, and `None` for open spans.
+    pub alloc: Option<AllocStats>,
     /// A list of spans that occurred inside this one
     pub children: Vec<Span>,
     /// A list of notes that occurred inside this span
     pub notes: Vec<Note>,
-    #[cfg_attr(feature = "json", serde(skip_serializing))]
+    /// Where the span was started, if it was started through
+    /// one of the `span!` or `guard!` macros
+    pub location: Option<Location>,
+    /// The async lane of a span that was started with `flame::begin`,
+    /// or `None` for the spans of the stack.  Spans on the same lane
+    /// don't overlap.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub lane: Option<u32>,
+    /// Names and values that describe the span, like the fields of
+    /// a `tracing` span
+    #[cfg_attr(feature = "json", serde(default))]
+    pub attributes: Vec<(String, String)>,
+    #[cfg_attr(feature = "json", serde(skip))]
     collapsable: bool,
-    #[cfg_attr(feature = "json", serde(skip_serializing))]
+    #[cfg_attr(feature = "json", serde(skip))]
     _priv: (),
 }
 
 /// A note for use in debugging.
+#[non_exhaustive]
 #[derive(Debug, Clone)]
-#[cfg_attr(feature = "json", derive(Serialize))]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
 pub struct Note {
     /// A short name describing what happened at some instant in time
     pub name: StrCow,
@@ -135,19 +293,117 @@ pub struct Note {
     pub description: Option<StrCow>,
     /// The time that the note was added
     pub instant: u64,
-    #[cfg_attr(feature = "json", serde(skip_serializing))]
-    _priv: (),
+    /// Where the note was added, if it was added through the `note!` macro
+    pub location: Option<Location>,
+    /// Names and values that describe the note, like the fields of
+    /// a `tracing` event
+    #[cfg_attr(feature = "json", serde(default))]
+    pub attributes: Vec<(String, String)>,
+}
+
+/// Controls which spans are reported by `spans_with` and `threads_with`.
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotOptions {
+    include_open: bool,
+}
+
+impl SnapshotOptions {
+    pub fn new() -> SnapshotOptions {
+        SnapshotOptions::default()
+    }
+
+    /// Report spans that haven't ended yet (and everything recorded
+    /// inside of them) instead of dropping them.
+    pub fn include_open(mut self, include_open: bool) -> SnapshotOptions {
+        self.include_open = include_open;
+        self
+    }
+}
+
+/// A position in the source code, as captured by the
+/// `span!`, `guard!` and `note!` macros.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct Location {
+    /// The file, as returned by `file!()`
+    pub file: StrCow,
+    /// The line, as returned by `line!()`
+    pub line: u32,
+    /// The module, as returned by `module_path!()`
+    pub module_path: StrCow,
+}
+
+impl Location {
+    pub fn new(file: &'static str, line: u32, module_path: &'static str) -> Location {
+        Location {
+            file: file.into(),
+            line,
+            module_path: module_path.into(),
+        }
+    }
+}
+
+impl ::std::fmt::Display for Location {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
 }
 
 /// A collection of events that happened on a single thread.
+#[non_exhaustive]
 #[derive(Debug, Clone)]
-#[cfg_attr(feature = "json", derive(Serialize))]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
 pub struct Thread {
     pub id: usize,
     pub name: Option<String>,
     pub spans: Vec<Span>,
-    #[cfg_attr(feature = "json", serde(skip_serializing))]
-    _priv: (),
+    /// The spans that were started with `flame::begin`, which can
+    /// overlap each other and the spans in `spans`
+    #[cfg_attr(feature = "json", serde(default))]
+    pub async_spans: Vec<Span>,
+    /// Notes that were recorded while no span was running, like the
+    /// ones of `FlameLogger`
+    #[cfg_attr(feature = "json", serde(default))]
+    pub notes: Vec<Note>,
+    /// The thread and span that started this thread, if it was started
+    /// through `flame::spawn`, `flame::scope` or `Context::attach`
+    #[cfg_attr(feature = "json", serde(default))]
+    pub parent: Option<Parent>,
+    /// The times at which the thread called `flame::frame_mark`, see
+    /// `flame::frames` for what happened in between
+    #[cfg_attr(feature = "json", serde(default))]
+    pub frame_marks: Vec<u64>,
+    /// The marks (see `flame::mark`) that this thread made, along with
+    /// the ones of threads outside of the snapshot if this is the first
+    /// thread.  They concern every thread.
+    #[cfg_attr(feature = "json", serde(default))]
+    pub marks: Vec<Mark>,
+}
+
+/// Where a thread was started from.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct Parent {
+    /// The id of the thread that started this one
+    pub thread_id: usize,
+    pub thread_name: Option<String>,
+    /// The name of the innermost span that was running on the parent
+    /// thread, if there was one
+    pub span: Option<StrCow>,
+    /// The start of that span, which tells it apart from other spans
+    /// with the same name
+    pub span_start_ns: Option<u64>,
+    /// The time that the parent thread captured the `Context`
+    pub instant: u64,
+}
+
+/// Identifies a span that was started with `begin`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SpanId {
+    thread_id: usize,
+    id: u32,
+    start_ns: u64,
 }
 
 #[must_use = "The guard is immediately dropped after instantiation. This is probably not
@@ -155,13 +411,14 @@ what you want! Consider using a `let` binding to increase its lifetime."]
 pub struct SpanGuard {
     name: Option<StrCow>,
     collapse: bool,
+    session: Session,
 }
 
 impl Drop for SpanGuard {
     fn drop(&mut self) {
         if ::std::thread::panicking() { return; }
         let name = self.name.take().unwrap();
-        end_impl(name, self.collapse);
+        self.session.end_impl(name, self.collapse);
     }
 }
 
@@ -172,36 +429,103 @@ impl SpanGuard {
     }
 }
 
-fn ns_since_epoch(epoch: Instant) -> u64 {
-    let elapsed = epoch.elapsed();
-    elapsed.as_secs() * 1000_000_000 + u64::from(elapsed.subsec_nanos())
+fn ns_since_epoch(epoch: u64) -> u64 {
+    clock::now_ns().saturating_sub(epoch)
 }
 
-fn convert_events_to_span<'a, I>(events: I) -> Vec<Span>
+/// Converts events into spans.  Open events are closed at `now`
+/// when the options ask for them and dropped along with their
+/// children otherwise.
+fn convert_events_to_span<'a, I>(events: I, options: &SnapshotOptions, now: u64) -> Vec<Span>
 where I: Iterator<Item = &'a Event> {
+    let conversion = Conversion {
+        options,
+        now,
+        correction: overhead::correction(),
+    };
     let mut iterator = events.peekable();
     let mut v = vec![];
     while let Some(event) = iterator.next() {
-        if let Some(span) = event_to_span(event, &mut iterator, 0) {
+        if let Some(span) = event_to_span(event, &mut iterator, 0, &conversion, &mut Recorded::default()) {
             v.push(span);
         }
     }
     v
 }
 
-fn event_to_span<'a, I: Iterator<Item = &'a Event>>(event: &Event, events: &mut Peekable<I>, depth: u16) -> Option<Span> {
-    if event.end_ns.is_some() && event.delta.is_some() {
+/// Converts events that were started with `begin` into spans, and
+/// puts each on the first lane that is free when it starts.
+fn convert_detached_events<'a, I>(events: I, options: &SnapshotOptions, now: u64) -> Vec<Span>
+where I: Iterator<Item = &'a Event> {
+    let conversion = Conversion {
+        options,
+        now,
+        correction: None,
+    };
+    let mut spans: Vec<Span> = events.filter_map(|event| {
+        event_to_span(event, &mut ::std::iter::empty().peekable(), 0, &conversion, &mut Recorded::default())
+    }).collect();
+    spans.sort_by_key(|span| span.start_ns);
+
+    // The end of the last span on every lane.
+    let mut lanes: Vec<u64> = vec![];
+    for span in &mut spans {
+        let lane = match lanes.iter().position(|&end| end <= span.start_ns) {
+            Some(lane) => lane,
+            None => {
+                lanes.push(0);
+                lanes.len() - 1
+            }
+        };
+        lanes[lane] = span.end_ns;
+        span.lane = Some(lane as u32);
+    }
+    spans
+}
+
+struct Conversion<'a> {
+    options: &'a SnapshotOptions,
+    now: u64,
+    correction: Option<Overhead>,
+}
+
+/// The number of spans and notes that were recorded inside of a span.
+#[derive(Default)]
+struct Recorded {
+    spans: u64,
+    notes: u64,
+}
+
+fn event_to_span<'a, I: Iterator<Item = &'a Event>>(event: &Event, events: &mut Peekable<I>, depth: u16,
+                                                    conversion: &Conversion, recorded: &mut Recorded) -> Option<Span> {
+    let now = conversion.now;
+    let (end_ns, delta, is_open) = match (event.end_ns, event.delta) {
+        (Some(end_ns), Some(delta)) => (end_ns, delta, false),
+        _ => (now, now.saturating_sub(event.start_ns), true),
+    };
+
+    {
         let mut span = Span {
             name: event.name.clone(),
             start_ns: event.start_ns,
-            end_ns: event.end_ns.unwrap(),
-            delta: event.delta.unwrap(),
+            end_ns,
+            delta,
+            raw_delta: delta,
+            is_open,
+            panicked: event.panicked,
             depth,
+            weight: event.weight,
+            cpu_ns: event.cpu_ns,
+            alloc: event.alloc,
             children: vec![],
             notes: event.notes.clone(),
+            location: event.location.clone(),
+            lane: None,
+            attributes: event.attributes.clone(),
             collapsable: event.collapse,
             _priv: ()
         };
+        let mut inside = Recorded { spans: 0, notes: event.notes.len() as u64 };
 
         loop {
             {
@@ -213,14 +537,23 @@ fn event_to_span<'a, I: Iterator<Item = &'a Event>>(event: &Event, events: &mut
             }
 
             let next = events.next().unwrap();
-            let child = event_to_span(next, events, depth + 1);
+            inside.spans += 1;
+            let child = event_to_span(next, events, depth + 1, conversion, &mut inside);
             if let Some(child) = child {
                 // Try to collapse with the previous span
                 if !span.children.is_empty() && child.collapsable && child.children.is_empty() {
                     let last = span.children.last_mut().unwrap();
-                    if last.name == child.name && last.depth == child.depth {
+                    if last.name == child.name && last.depth == child.depth && last.weight == child.weight {
                         last.end_ns = child.end_ns;
                         last.delta += child.delta;
+                        last.raw_delta += child.raw_delta;
+                        last.cpu_ns = match (last.cpu_ns, child.cpu_ns) {
+                            (Some(last), Some(child)) => Some(last + child),
+                            _ => None,
+                        };
+                        if let (Some(last), Some(child)) = (last.alloc.as_mut(), child.alloc) {
+                            last.merge(&child);
+                        }
                         continue;
                     }
                 }
@@ -229,13 +562,34 @@ fn event_to_span<'a, I: Iterator<Item = &'a Event>>(event: &Event, events: &mut
                 span.children.push(child);
             }
         }
-        Some(span)
-    } else {
-        None
+
+        if let Some(overhead) = conversion.correction {
+            let cost = inside.spans * overhead.span_ns + inside.notes * overhead.note_ns;
+            span.delta = span.delta.saturating_sub(cost);
+        }
+        recorded.spans += inside.spans;
+        recorded.notes += inside.notes;
+
+        if is_open && !conversion.options.include_open {
+            None
+        } else {
+            Some(span)
+        }
     }
 }
 
+#[cfg(feature = "json")]
+fn one() -> u64 {
+    1
+}
+
 impl Span {
+    /// The estimated total time of all occurrences of this span, that is
+    /// `delta` scaled by `weight`.
+    pub fn weighted_delta(&self) -> u64 {
+        self.delta.saturating_mul(self.weight)
+    }
+
     #[cfg(feature = "json")]
     pub fn into_json(&self) -> String {
         ::serde_json::to_string_pretty(self).unwrap()
@@ -255,51 +609,90 @@ impl Thread {
 }
 
 impl Library {
-    fn new() -> Library {
+    fn new(epoch: u64) -> Library {
         Library {
+            id: ::thread_id::get(),
             name: ::std::thread::current().name().map(Into::into),
-            current: PrivateFrame {
-                all: vec![],
-                id_stack: vec![],
-                next_id: 0,
-            },
-            epoch: Instant::now(),
+            current: PrivateFrame::new(),
+            frames: FrameHistory::new(),
+            epoch,
         }
     }
+
+    fn clear(&mut self) {
+        self.current = PrivateFrame::new();
+        self.frames = FrameHistory::new();
+    }
+
+    /// Like `clear`, but keeps the spans that are still running so that
+    /// the thread can end them later.
+    fn clear_finished(&mut self) {
+        let current = &mut self.current;
+        // Only the spans on the stack can still be ended.  A span that a
+        // mismatched `end` took off the stack is dropped, and a span whose
+        // parent was ended first (see `end_event_by_id`) moves under its
+        // closest running ancestor.
+        let open: Vec<u32> = current.open_ids().collect();
+        let renumber = |id: u32| open.iter().position(|&open| open == id).map(|position| position as u32);
+        let parents: Vec<Option<u32>> = open.iter().map(|&id| {
+            let mut parent = current.all[id as usize].parent;
+            while let Some(id) = parent {
+                if let Some(renumbered) = renumber(id) {
+                    return Some(renumbered);
+                }
+                parent = current.all[id as usize].parent;
+            }
+            None
+        }).collect();
+        let mut kept: Vec<Event> = current.all.drain(..)
+            .filter_map(|mut event| {
+                let id = renumber(event.id)?;
+                event.id = id;
+                event.parent = parents[id as usize];
+                event.notes.clear();
+                Some(event)
+            })
+            .collect();
+        kept.sort_by_key(|event| event.id);
+        for (position, id) in current.id_stack.iter_mut().enumerate() {
+            *id = position as u32;
+        }
+        current.next_id = kept.len() as u32;
+        current.all = kept;
+        current.notes.clear();
+        current.frame_marks.clear();
+        current.detached.retain(|event| event.end_ns.is_none());
+        self.frames = FrameHistory::new();
+    }
 }
 
-fn commit_impl(library: &mut Library) {
-    use std::thread;
-    use std::sync::MutexGuard;
+/// Takes the recorded frame out of `library` so that it can be
+/// committed, or returns `None` if nothing was recorded.
+fn take_frame(library: &mut Library) -> Option<CommittedFrame> {
     use std::mem;
-    
-    let mut frame = PrivateFrame {
-        all: vec![],
-        id_stack: vec![],
-        next_id: 0,
-    };
+
+    let mut frame = PrivateFrame::new();
 
     mem::swap(&mut frame, &mut library.current);
-    if frame.all.is_empty() {
-        return;
+    if frame.is_empty() {
+        return None;
     }
 
-    if let Ok(mut handle) = ALL_THREADS.lock() {
-        let thread_name = library.name.clone();
-        let thread_id = ::thread_id::get();
-        handle.push((thread_id, thread_name, frame))
-    }
+    Some(CommittedFrame {
+        thread_id: library.id,
+        thread_name: library.name.clone(),
+        committed_ns: ns_since_epoch(library.epoch),
+        frame,
+    })
 }
 
+/// Moves everything recorded on this thread so far into the current
+/// session's list of finished threads.
+///
+/// Live threads are visible to `threads()` without this, so it is only
+/// needed to hand the recorded spans over before the thread exits.
 pub fn commit_thread() {
-    LIBRARY.with(|library| commit_impl(&mut *library.borrow_mut()));
-}
-
-impl Drop for Library {
-    fn drop(&mut self) {
-        if ::std::thread::panicking() { return; }
-        commit_impl(self);
-    }
+    session::with_current(Session::commit_thread);
 }
 
 /// Starts a `Span` and also returns a `SpanGuard`.
@@ -307,9 +700,26 @@ impl Drop for Library {
 /// When the `SpanGuard` is dropped (or `.end()` is called on it),
 /// the span will automatically be ended.
 pub fn start_guard<S: Into<StrCow>>(name: S) -> SpanGuard {
+    session::with_current(|session| session.start_guard(name))
+}
+
+/// Like `start_guard`, but records where the span was started.
+///
+/// Usually called through the `guard!` macro.
+pub fn start_guard_at<S: Into<StrCow>>(name: S, location: Location) -> SpanGuard {
     let name = name.into();
-    start(name.clone());
-    SpanGuard { name: Some(name), collapse: false }
+    session::with_current(|session| {
+        session.start_at(name.clone(), Some(location));
+        SpanGuard { name: Some(name), collapse: false, session: session.clone() }
+    })
+}
+
+/// Starts a `Span` and also returns a `SpanGuard` that ends
+/// the span like `end_collapse` when it is dropped.
+pub fn start_guard_collapse<S: Into<StrCow>>(name: S) -> SpanGuard {
+    let mut guard = start_guard(name);
+    guard.collapse = true;
+    guard
 }
 
 /// Starts and ends a `Span` that lasts for the duration of the
@@ -325,68 +735,259 @@ F: FnOnce() -> R
     r
 }
 
+/// Wraps a future so that every call to `poll` is recorded as a
+/// `Span` with the given name.
+///
+/// Spans are started and ended inside of `poll`, so they never stay
+/// open while the future is suspended.
+pub fn instrument<S, F>(name: S, future: F) -> Instrumented<F> where
+S: Into<StrCow>,
+F: Future
+{
+    instrument_with(Some(name), false, future)
+}
+
+/// Like `instrument`, but the future is left untimed when `name` is `None`
+/// and the per-poll spans are collapsed when `collapse` is `true`.
+///
+/// This is mainly useful for code generation, see the `macros` feature.
+pub fn instrument_with<S, F>(name: Option<S>, collapse: bool, future: F) -> Instrumented<F> where
+S: Into<StrCow>,
+F: Future
+{
+    Instrumented { name: name.map(Into::into), collapse, future }
+}
+
+/// A future that records a `Span` for every `poll`, created by `instrument`.
+#[must_use = "futures do nothing unless polled"]
+pub struct Instrumented<F> {
+    name: Option<StrCow>,
+    collapse: bool,
+    future: F,
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext) -> Poll<F::Output> {
+        // Safety: `future` is never moved out of `self`.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        match this.name {
+            Some(ref name) => {
+                start(name.clone());
+                let result = future.poll(cx);
+                end_impl(name.clone(), this.collapse);
+                result
+            }
+            None => future.poll(cx),
+        }
+    }
+}
+
+/// Starts a `Span` named after the enclosing function that lasts
+/// until the end of the enclosing block.
+///
+/// ```
+/// #[macro_use]
+/// extern crate flame;
+///
+/// fn work() {
+///     flame_fn!();
+///     // ...
+/// }
+/// # fn main() { work(); }
+/// ```
+#[macro_export]
+macro_rules! flame_fn {
+    () => {
+        let _flame_guard = $crate::start_guard({
+            fn f() {}
+            fn type_name_of<T>(_: T) -> &'static str {
+                ::std::any::type_name::<T>()
+            }
+            let name = type_name_of(f);
+            &name[..name.len() - 3]
+        });
+    };
+}
+
+/// Starts a new Span that records the file, line and module
+/// it was started from.
+///
+/// ```
+/// #[macro_use]
+/// extern crate flame;
+///
+/// # fn main() {
+/// span!("parse");
+/// flame::end("parse");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! span {
+    ($name:expr) => {
+        $crate::start_at($name, $crate::Location::new(file!(), line!(), module_path!()))
+    };
+}
+
+/// Starts a `Span` that records the file, line and module it was
+/// started from, and returns a `SpanGuard` that ends it.
+///
+/// ```
+/// #[macro_use]
+/// extern crate flame;
+///
+/// # fn main() {
+/// let _guard = guard!("parse");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! guard {
+    ($name:expr) => {
+        $crate::start_guard_at($name, $crate::Location::new(file!(), line!(), module_path!()))
+    };
+}
+
+/// Records a note on the current Span along with the file, line
+/// and module it was recorded from.
+///
+/// ```
+/// #[macro_use]
+/// extern crate flame;
+///
+/// # fn main() {
+/// let _guard = guard!("fetch");
+/// note!("cache miss");
+/// note!("retry", "connection reset");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! note {
+    ($name:expr) => {
+        $crate::note_at($name, None, $crate::Location::new(file!(), line!(), module_path!()))
+    };
+    ($name:expr, $description:expr) => {
+        $crate::note_at($name, Some($description), $crate::Location::new(file!(), line!(), module_path!()))
+    };
+}
+
 /// Starts a new Span
 pub fn start<S: Into<StrCow>>(name: S) {
-    LIBRARY.with(|library| {
-        let mut library = library.borrow_mut();
-        let epoch = library.epoch;
+    start_impl(name.into(), None);
+}
 
-        let collector = &mut library.current;
-        let id = collector.next_id;
-        collector.next_id += 1;
-
-        let this = Event {
-            id,
-            parent: collector.id_stack.last().cloned(),
-            name: name.into(),
-            collapse: false,
-            start_ns: ns_since_epoch(epoch),
-            end_ns: None,
-            delta: None,
-            notes: vec![]
-        };
+/// Starts a new Span and records where it was started.
+///
+/// Usually called through the `span!` macro.
+pub fn start_at<S: Into<StrCow>>(name: S, location: Location) {
+    start_impl(name.into(), Some(location));
+}
 
-        collector.all.push(this);
-        collector.id_stack.push(id);
+fn start_impl(name: StrCow, location: Option<Location>) {
+    session::with_current(|session| session.start_at(name, location));
+}
+
+/// Starts an event that sampling decided to record with `weight`, see
+/// `Session::sample`, and returns its id.
+fn start_event(library: &mut Library, name: StrCow, location: Option<Location>, weight: u32) -> u32 {
+    let epoch = library.epoch;
+    let collector = &mut library.current;
+    let parent = collector.id_stack.last().cloned();
+    let id = collector.next_id;
+    collector.next_id += 1;
+    let weight = u64::from(weight);
+    let weight = match parent {
+        Some(parent) => weight * collector.event_mut(parent).weight,
+        None => weight,
+    };
+
+    let start_ns = ns_since_epoch(epoch);
+    let thread_id = library.id as u64;
+    stream::emit(|| LiveEvent::Start {
+        thread_id,
+        timestamp_ns: epoch + start_ns,
+        name: name.to_string(),
+        location: location.clone(),
     });
+
+    let this = Event {
+        id,
+        parent,
+        name,
+        collapse: false,
+        start_ns,
+        end_ns: None,
+        delta: None,
+        notes: vec![],
+        location,
+        panicked: false,
+        weight,
+        cpu_start_ns: clock::thread_cpu_ns(),
+        cpu_ns: None,
+        alloc_start: None,
+        alloc: None,
+        attributes: vec![],
+    };
+
+    collector.all.push(this);
+    collector.id_stack.push(id);
+    // Snapshot last so that recording the event isn't counted.
+    collector.event_mut(id).alloc_start = alloc::start();
+    id
 }
 
 fn end_impl<S: Into<StrCow>>(name: S, collapse: bool) -> u64 {
-    use std::thread;
-
     let name = name.into();
-    let delta = LIBRARY.with(|library| {
-        let mut library = library.borrow_mut();
-        let epoch = library.epoch;
-        let collector = &mut library.current;
+    session::with_current(|session| session.end_impl(name, collapse))
+}
 
-        let current_id = match collector.id_stack.pop() {
-            Some(id) => id,
-            None if thread::panicking() => 0,
-            None => panic!("flame::end({:?}) called without a currently running span!", &name)
-        };
+fn end_event(library: &mut Library, name: &StrCow, collapse: bool) -> Result<Option<u64>, String> {
+    use std::thread;
 
-        let event = &mut collector.all[current_id as usize];
+    let epoch = library.epoch;
+    let collector = &mut library.current;
 
-        if event.name != name {
-            panic!("flame::end({}) attempted to end {}", &name, event.name);
-        }
+    let current_id = match collector.id_stack.pop() {
+        Some(id) => id,
+        None if thread::panicking() => return Ok(None),
+        None => return Err(format!("flame::end({:?}) called without a currently running span!", name))
+    };
 
-        let timestamp = ns_since_epoch(epoch);
-        event.end_ns = Some(timestamp);
-        event.collapse = collapse;
-        event.delta = Some(timestamp - event.start_ns);
-        event.delta
-    });
+    let event = collector.event_mut(current_id);
 
-    match delta {
-        Some(d) => d,
-        None => 0, // panicking
+    if event.name != *name {
+        return Err(format!("flame::end({}) attempted to end {}", name, event.name));
     }
+
+    Ok(Some(close_event(library, current_id, collapse)))
+}
+
+/// Ends the event `id`, which was just taken off the stack, and returns
+/// its duration.
+fn close_event(library: &mut Library, id: u32, collapse: bool) -> u64 {
+    let epoch = library.epoch;
+    let thread_id = library.id as u64;
+    let event = library.current.event_mut(id);
+    event.alloc = event.alloc_start.as_ref().map(alloc::finish);
+    let timestamp = ns_since_epoch(epoch);
+    event.end_ns = Some(timestamp);
+    event.collapse = collapse;
+    let delta = timestamp.saturating_sub(event.start_ns);
+    event.delta = Some(delta);
+    event.cpu_ns = event.cpu_start_ns.and_then(|start| Some(clock::thread_cpu_ns()?.saturating_sub(start)));
+    stream::emit(|| LiveEvent::End {
+        thread_id,
+        timestamp_ns: epoch + timestamp,
+        name: event.name.to_string(),
+    });
+    delta
 }
 
 /// Ends the current Span and returns the number
 /// of nanoseconds that passed.
+///
+/// Returns 0 if the span was skipped by sampling.
 pub fn end<S: Into<StrCow>>(name: S) -> u64 {
     end_impl(name, false)
 }
@@ -417,143 +1018,391 @@ pub fn end_collapse<S: Into<StrCow>>(name: S) -> u64 {
     end_impl(name, true)
 }
 
-/// Records a note on the current Span.
-pub fn note<S: Into<StrCow>>(name: S, description: Option<S>) {
+/// Starts a span that doesn't have to end before the spans that were
+/// started after it, and returns the id to end it with `finish`.
+///
+/// Spans like this overlap rather than nest, which suits several requests
+/// that are in flight on one thread at the same time.  They don't have
+/// children and are reported in `Thread::async_spans`, on as many lanes
+/// as it takes for the spans on one lane not to overlap.
+///
+/// ```
+/// let first = flame::begin("request 1");
+/// let second = flame::begin("request 2");
+/// flame::finish(first);
+/// flame::finish(second);
+/// ```
+pub fn begin<S: Into<StrCow>>(name: S) -> SpanId {
     let name = name.into();
-    let description = description.map(Into::into);
+    session::with_current(|session| session.begin(name))
+}
 
-    LIBRARY.with(|library| {
-        let mut library = library.borrow_mut();
-        let epoch = library.epoch;
+/// Ends a span that was started with `begin` and returns the number of
+/// nanoseconds that passed.
+///
+/// # Panics
+///
+/// Panics if the span was started on another thread or has already
+/// finished.
+pub fn finish(id: SpanId) -> u64 {
+    session::with_current(|session| session.finish(id))
+}
 
-        let collector = &mut library.current;
+fn begin_event(library: &mut Library, name: StrCow) -> SpanId {
+    let epoch = library.epoch;
+    let thread_id = library.id as u64;
+    let start_ns = ns_since_epoch(epoch);
+    let collector = &mut library.current;
+    let id = collector.next_detached_id;
+    collector.next_detached_id += 1;
+    stream::emit(|| LiveEvent::Begin {
+        thread_id,
+        timestamp_ns: epoch + start_ns,
+        name: name.to_string(),
+        id,
+    });
+    collector.detached.push(Event {
+        id,
+        parent: None,
+        name,
+        collapse: false,
+        start_ns,
+        end_ns: None,
+        delta: None,
+        notes: vec![],
+        location: None,
+        panicked: false,
+        weight: 1,
+        cpu_start_ns: None,
+        cpu_ns: None,
+        alloc_start: None,
+        alloc: None,
+        attributes: vec![],
+    });
+    SpanId { thread_id: library.id, id, start_ns }
+}
 
-        let current_id = match collector.id_stack.last() {
-            Some(id) => *id,
-            None => panic!("flame::note({}, {:?}) called without a currently running span!",
-                           &name, &description)
-        };
+fn finish_event(library: &mut Library, id: SpanId) -> Result<u64, String> {
+    if id.thread_id != library.id {
+        return Err(format!("flame::finish({:?}) called on another thread than flame::begin", id));
+    }
+    let epoch = library.epoch;
+    let timestamp = ns_since_epoch(epoch);
+    let detached = &mut library.current.detached;
+    let event = match detached.binary_search_by_key(&id.id, |event| event.id) {
+        Ok(index) if detached[index].start_ns == id.start_ns && detached[index].end_ns.is_none() => &mut detached[index],
+        _ => return Err(format!("flame::finish({:?}) called for a span that isn't running", id)),
+    };
+    event.end_ns = Some(timestamp);
+    let delta = timestamp.saturating_sub(event.start_ns);
+    event.delta = Some(delta);
+    stream::emit(|| LiveEvent::Finish {
+        thread_id: id.thread_id as u64,
+        timestamp_ns: epoch + timestamp,
+        name: event.name.to_string(),
+        id: id.id,
+    });
+    Ok(delta)
+}
 
-        let event = &mut collector.all[current_id as usize];
-        event.notes.push(Note {
-            name,
-            description,
-            instant: ns_since_epoch(epoch),
-            _priv: ()
-        });
+/// Records a note on the current Span, or on the current thread (see
+/// `Thread::notes`) if no span is running.
+pub fn note<S: Into<StrCow>>(name: S, description: Option<S>) {
+    note_impl(name.into(), description.map(Into::into), None);
+}
+
+/// Records a note on the current Span along with where it was recorded.
+///
+/// Usually called through the `note!` macro.
+pub fn note_at<S: Into<StrCow>>(name: S, description: Option<S>, location: Location) {
+    note_impl(name.into(), description.map(Into::into), Some(location));
+}
+
+fn note_impl(name: StrCow, description: Option<StrCow>, location: Option<Location>) {
+    session::with_current(|session| session.note_at(name, description, location));
+}
+
+/// Records a note on the innermost running span, or on the thread
+/// itself if no span is running.
+fn note_event(library: &mut Library, name: StrCow, description: Option<StrCow>,
+              location: Option<Location>, attributes: Vec<(String, String)>) {
+    let epoch = library.epoch;
+    let current_id = library.current.id_stack.last().cloned();
+
+    let instant = ns_since_epoch(epoch);
+    let thread_id = library.id as u64;
+    stream::emit(|| LiveEvent::Note {
+        thread_id,
+        timestamp_ns: epoch + instant,
+        name: name.to_string(),
+        description: description.as_ref().map(|description| description.to_string()),
+        location: location.clone(),
     });
+
+    let note = Note {
+        name,
+        description,
+        instant,
+        location,
+        attributes,
+    };
+    match current_id {
+        Some(id) => library.current.event_mut(id).notes.push(note),
+        None => library.current.notes.push(note),
+    }
 }
 
-/// Clears all of the recorded info that Flame has
-/// tracked.
-pub fn clear() {
-    if ::std::thread::panicking() { return; }
-    LIBRARY.with(|library| {
-        let mut library = library.borrow_mut();
-        library.current = PrivateFrame {
-            all: vec![],
-            id_stack: vec![],
-            next_id: 0,
-        };
-        library.epoch = Instant::now();
+/// Finds the position on the stack of the running span that was started
+/// as `id` at `start_ns`, or `None` if it isn't running any more.
+#[cfg(feature = "tracing")]
+fn find_running(library: &Library, id: u32, start_ns: u64) -> Option<usize> {
+    let collector = &library.current;
+    let started_at = |open: u32| collector.all[open as usize].start_ns == start_ns;
+    collector.id_stack.iter().rposition(|&open| open == id && started_at(open))
+        // `clear` renumbers the spans that other threads are running.
+        .or_else(|| collector.id_stack.iter().rposition(|&open| started_at(open)))
+}
+
+/// Replaces the attributes of the running span that was started as `id`
+/// at `start_ns`, if it is still running.
+#[cfg(feature = "tracing")]
+fn set_attributes(library: &mut Library, id: u32, start_ns: u64, attributes: Vec<(String, String)>) {
+    if let Some(position) = find_running(library, id, start_ns) {
+        let id = library.current.id_stack[position];
+        library.current.event_mut(id).attributes = attributes;
+    }
+}
+
+/// Ends the running span that was started as `id` at `start_ns`, even if
+/// spans that were started inside of it are still running.
+#[cfg(feature = "tracing")]
+fn end_event_by_id(library: &mut Library, id: u32, start_ns: u64) {
+    if let Some(position) = find_running(library, id, start_ns) {
+        let id = library.current.id_stack.remove(position);
+        close_event(library, id, false);
+    }
+}
+
+/// Installs a panic hook that ends every open span on the panicking
+/// thread and commits the thread to every session it recorded into,
+/// so that its spans show up in `threads()`.
+///
+/// The ended spans are marked as `panicked`, and the panic message
+/// and location are added as a note to the innermost one.  The
+/// previously installed hook is called afterwards.  Installing the
+/// hook more than once has no effect.
+pub fn install_panic_hook() {
+    use std::panic;
+    use std::sync::Once;
+
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            record_panic(info);
+            previous(info);
+        }));
+    });
+}
+
+#[allow(deprecated)] // `PanicInfo` is `PanicHookInfo` on newer compilers
+fn record_panic(info: &::std::panic::PanicInfo) {
+    let message = if let Some(message) = info.payload().downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = info.payload().downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_owned()
+    };
+    let location = info.location().map(|location| Location {
+        file: location.file().to_owned().into(),
+        line: location.line(),
+        module_path: "".into(),
     });
 
-    let mut handle = ALL_THREADS.lock().unwrap();
-    handle.clear();
+    session::commit_all_for_panic(|library| {
+        if library.current.id_stack.is_empty() {
+            return false;
+        }
+
+        let epoch = library.epoch;
+        let thread_id = library.id as u64;
+        let timestamp = ns_since_epoch(epoch);
+        let cpu_now = clock::thread_cpu_ns();
+        let collector = &mut library.current;
+        if let Some(innermost) = collector.open_ids().last() {
+            collector.event_mut(innermost).notes.push(Note {
+                name: "panic".into(),
+                description: Some(message.clone().into()),
+                instant: timestamp,
+                location: location.clone(),
+                attributes: vec![],
+            });
+        }
+        for id in ::std::mem::take(&mut collector.id_stack).into_iter().rev() {
+            let event = collector.event_mut(id);
+            event.alloc = event.alloc_start.as_ref().map(alloc::finish);
+            event.end_ns = Some(timestamp);
+            event.delta = Some(timestamp.saturating_sub(event.start_ns));
+            event.panicked = true;
+            event.cpu_ns = event.cpu_start_ns.and_then(|start| Some(cpu_now?.saturating_sub(start)));
+            stream::emit(|| LiveEvent::End {
+                thread_id,
+                timestamp_ns: epoch + timestamp,
+                name: event.name.to_string(),
+            });
+        }
+        true
+    });
+}
+
+/// Ends the current frame of this thread and starts the next one.
+///
+/// Everything that finished since the previous call becomes a numbered
+/// `FrameProfile`, see `frames()`, including the children of a span
+/// that wraps the whole loop.  Spans that are still running end up in
+/// a later frame.  Only the most recent frames are kept, see
+/// `set_frame_history`, while the spans stay in `spans()` and the
+/// dumps, which show the frame boundaries from `Thread::frame_marks`.
+///
+/// ```
+/// for _ in 0 .. 3 {
+///     flame::span_of("update", || ());
+///     flame::span_of("render", || ());
+///     flame::frame_mark();
+/// }
+///
+/// let frames = flame::frames();
+/// assert_eq!(frames.len(), 3);
+/// assert_eq!(frames[2].spans.len(), 2);
+/// let slowest = flame::slowest_frames(&frames, 1);
+/// ```
+pub fn frame_mark() {
+    session::with_current(Session::frame_mark);
+}
+
+/// Returns the frames of this thread that were ended by `frame_mark`,
+/// oldest first.
+pub fn frames() -> Vec<FrameProfile> {
+    session::with_current(Session::frames)
+}
+
+/// Sets how many frames are kept per thread by `frame_mark`.
+/// Defaults to `DEFAULT_FRAME_HISTORY`.
+pub fn set_frame_history(frames: usize) {
+    session::with_current(|session| session.set_frame_history(frames));
+}
+
+/// Only records one in `every` occurrences of spans named `name`.
+///
+/// Skipped occurrences (and everything inside of them) are only
+/// counted, the recorded ones get a `weight` of `every` so that
+/// aggregates over `Span::weighted_delta` stay correct.  An `every`
+/// of 0 or 1 records every occurrence again.
+///
+/// ```
+/// flame::sample("parse token", 100);
+/// flame::span_of("parse", || {
+///     for _ in 0 .. 1000 {
+///         flame::span_of("parse token", || ());
+///     }
+/// });
+///
+/// let tokens = &flame::spans()[0].children;
+/// assert_eq!(tokens.len(), 10);
+/// assert_eq!(tokens[0].weight, 100);
+/// # flame::clear_sampling();
+/// ```
+pub fn sample<S: AsRef<str>>(name: S, every: u32) {
+    session::with_current(|session| session.sample(name, every));
+}
+
+/// Limits how often each span name is recorded per thread.
+///
+/// Names that occur more than `events_per_second` times per second are
+/// sampled like with `sample`, with the rate adapted once a second.
+/// `None` turns adaptive sampling off again.
+pub fn sample_adaptive(events_per_second: Option<u32>) {
+    session::with_current(|session| session.sample_adaptive(events_per_second));
+}
+
+/// Removes all sampling rules, so that every span is recorded again.
+pub fn clear_sampling() {
+    session::with_current(Session::clear_sampling);
+}
+
+/// Clears all of the recorded info that Flame has
+/// tracked, see `Session::clear`.
+pub fn clear() {
+    session::with_current(Session::clear);
 }
 
 /// Returns a list of spans from the current thread
 pub fn spans() -> Vec<Span> {
-    if ::std::thread::panicking() { return vec![]; }
-    LIBRARY.with(|library| {
-        let library = library.borrow();
-        let cur = &library.current;
-        convert_events_to_span(cur.all.iter())
-    })
+    spans_with(&SnapshotOptions::default())
 }
 
+/// Returns a list of spans from the current thread, filtered
+/// according to `options`.
+pub fn spans_with(options: &SnapshotOptions) -> Vec<Span> {
+    session::with_current(|session| session.spans_with(options))
+}
+
+fn library_spans(library: &Library, options: &SnapshotOptions) -> Vec<Span> {
+    let now = ns_since_epoch(library.epoch);
+    convert_events_to_span(library.current.all.iter(), options, now)
+}
+
+fn library_async_spans(library: &Library, options: &SnapshotOptions) -> Vec<Span> {
+    let now = ns_since_epoch(library.epoch);
+    convert_detached_events(library.current.detached.iter(), options, now)
+}
+
+/// Returns a snapshot of every thread that recorded something.
+///
+/// The calling thread always comes first, followed by threads that are
+/// still running and finally threads that have exited or called
+/// `commit_thread`.
 pub fn threads() -> Vec<Thread> {
-    if ::std::thread::panicking() { return vec![]; }
-
-    let my_thread_name = ::std::thread::current().name().map(Into::into);
-    let my_thread_id = ::thread_id::get();
-
-    let mut out = vec![ Thread {
-        id: my_thread_id,
-        name: my_thread_name,
-        spans: spans(),
-        _priv: (),
-    }];
-
-    if let Ok(mut handle) = ALL_THREADS.lock() {
-        for &(id, ref name, ref frm) in &*handle {
-            out.push(Thread {
-                id,
-                name: name.clone(),
-                spans: convert_events_to_span(frm.all.iter()),
-                _priv: (),
-            });
-        }
-    }
+    threads_with(&SnapshotOptions::default())
+}
 
-    out
+/// Like `threads`, but the spans are filtered according to `options`.
+pub fn threads_with(options: &SnapshotOptions) -> Vec<Thread> {
+    session::with_current(|session| session.threads_with(options))
 }
 
 /// Prints all of the frames to stdout.
 pub fn debug() {
     if ::std::thread::panicking() { return; }
-    LIBRARY.with(|library| {
+    session::with_current(|session| session.with_library(|library| {
         println!("{:?}", library);
-    });
+    }));
 }
 
-pub fn dump_text_to_writer<W: Write>(mut out: W) -> Result<(), IoError>  {
-    fn print_span<W: Write>(span: &Span, out: &mut W) -> Result<f32, IoError> {
-        let mut buf = String::new();
-        for _ in 0 .. span.depth {
-            buf.push_str("  ");
-        }
-        buf.push_str("| ");
-        let ms = span.delta as f32 / 1000000.0;
-        buf.push_str(&format!("{}: {}ms", span.name, ms));
-        writeln!(out, "{}", buf)?;
-        let mut missing = ms;
-        for child in &span.children {
-            missing -= print_span(child, out)?;
-        }
-
-        if !span.children.is_empty() {
-            let mut buf = String::new();
-            for _ in 0 ..= span.depth {
-                buf.push_str("  ");
-            }
-            buf.push_str("+ ");
-            buf.push_str(&format!("{}ms", missing));
-            writeln!(out, "{}", buf)?;
-        }
-
-        Ok(ms)
-    }
-
-    for thread in threads() {
-        writeln!(out, "THREAD: {}", thread.id)?;
-        for span in thread.spans {
-            print_span(&span, &mut out)?;
-        }
-        writeln!(out)?;
-    }
-    Ok(())
+pub fn dump_text_to_writer<W: Write>(out: W) -> Result<(), IoError>  {
+    session::with_current(|session| session.dump_text_to_writer(out))
 }
 
 pub fn dump_stdout() {
-    let stdout = ::std::io::stdout();
-    let stdout = stdout.lock();
-    dump_text_to_writer(stdout);
+    session::with_current(Session::dump_stdout);
 }
 
 #[cfg(feature="json")]
 pub fn dump_json<W: std::io::Write>(out: &mut W) -> std::io::Result<()> {
-    out.write_all(serde_json::to_string_pretty(&threads()).unwrap().as_bytes())
+    session::with_current(|session| session.dump_json(out))
+}
+
+/// Writes the threads of the current session that `threads_with(options)`
+/// returns as JSON.
+#[cfg(feature="json")]
+pub fn dump_json_with<W: std::io::Write>(out: &mut W, options: &SnapshotOptions) -> std::io::Result<()> {
+    session::with_current(|session| session.dump_json_with(out, options))
 }
 
-pub use html::{dump_html, dump_html_custom};
+pub use html::{HtmlOptions, dump_html, dump_html_custom, dump_html_threads, dump_html_threads_with, dump_html_with};
+pub use timeline::{dump_timeline, dump_timeline_custom, dump_timeline_with_marks};
+pub use svg::{Orientation, Palette, SvgOptions, dump_svg, dump_svg_threads};
+pub use text::{ColorChoice, SortOrder, TextReportOptions, TimeUnit, dump_text_custom, dump_text_with, dump_stdout_with};
+#[cfg(feature = "macros")]
+pub use flame_macros::{flame, noflame};
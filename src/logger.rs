@@ -0,0 +1,94 @@
+//! Records `log` records as notes.
+
+use log::{self, Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+use super::Location;
+
+/// A logger that passes every record on to another logger and also
+/// records the ones at or above a level as notes.
+///
+/// A record becomes a note on the innermost running span of the
+/// calling thread, or on the thread itself (see `Thread::notes`) if no
+/// span is running.  The message is the name of the note and the level
+/// and target are kept as its `attributes`.
+///
+/// ```no_run
+/// # extern crate flame;
+/// # extern crate log;
+/// # struct Stderr;
+/// # impl log::Log for Stderr {
+/// #     fn enabled(&self, _: &log::Metadata) -> bool { true }
+/// #     fn log(&self, record: &log::Record) { eprintln!("{}", record.args()); }
+/// #     fn flush(&self) {}
+/// # }
+/// # fn main() {
+/// flame::FlameLogger::new(Stderr).level(log::LevelFilter::Warn).init().unwrap();
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct FlameLogger<L> {
+    inner: L,
+    level: LevelFilter,
+}
+
+impl<L: Log> FlameLogger<L> {
+    /// Wraps `inner`, recording records at `Info` and above.
+    pub fn new(inner: L) -> FlameLogger<L> {
+        FlameLogger { inner, level: LevelFilter::Info }
+    }
+
+    /// The level that records need to be recorded as notes.
+    pub fn level(mut self, level: LevelFilter) -> FlameLogger<L> {
+        self.level = level;
+        self
+    }
+
+    /// Installs the logger as the global logger.
+    ///
+    /// Raises the maximum level of the `log` crate to the level of this
+    /// logger if it is lower.
+    pub fn init(self) -> Result<(), SetLoggerError> where L: 'static {
+        let level = self.level;
+        log::set_boxed_logger(Box::new(self))?;
+        if level > log::max_level() {
+            log::set_max_level(level);
+        }
+        Ok(())
+    }
+
+    fn records(&self, level: Level) -> bool {
+        level <= self.level
+    }
+}
+
+impl<L: Log> Log for FlameLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.records(metadata.level()) || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.records(record.level()) {
+            let location = match (record.file(), record.line()) {
+                (Some(file), Some(line)) => Some(Location {
+                    file: file.to_owned().into(),
+                    line,
+                    module_path: record.module_path().unwrap_or("").to_owned().into(),
+                }),
+                _ => None,
+            };
+            let attributes = vec![
+                ("level".to_owned(), record.level().to_string()),
+                ("target".to_owned(), record.target().to_owned()),
+            ];
+            let name = record.args().to_string();
+            ::session::with_current(|session| session.note_with(name.into(), None, location, attributes));
+        }
+        if self.inner.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
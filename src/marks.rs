@@ -0,0 +1,69 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use super::StrCow;
+use clock;
+
+/// How many marks are kept, older ones are dropped.
+pub const MAX_MARKS: usize = 4096;
+
+lazy_static! {
+    /// Every mark with the clock time it was made at and the thread
+    /// that made it.
+    static ref MARKS: Mutex<VecDeque<(u64, usize, StrCow)>> = Mutex::new(VecDeque::new());
+}
+
+/// An instant that concerns the whole process rather than one thread,
+/// see `flame::mark`.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "json", derive(Serialize, Deserialize))]
+pub struct Mark {
+    pub name: StrCow,
+    /// The time of the mark, relative to the epoch of the session
+    pub instant: u64,
+    /// The thread that made the mark
+    #[cfg_attr(feature = "json", serde(default))]
+    pub thread_id: usize,
+}
+
+/// Marks an instant for the whole process, like "config reloaded".
+///
+/// Unlike notes, marks don't belong to any span.  Every session sees
+/// them (see `Session::marks` and `Thread::marks`) and the timeline,
+/// Chrome and HTML exports show them across all threads.  They are kept
+/// until `clear_marks` is called or `MAX_MARKS` newer marks were made.
+pub fn mark<S: Into<StrCow>>(name: S) {
+    let now = clock::now_ns();
+    let mut marks = lock();
+    if marks.len() >= MAX_MARKS {
+        marks.pop_front();
+    }
+    marks.push_back((now, ::thread_id::get(), name.into()));
+}
+
+/// Forgets every mark.
+pub fn clear_marks() {
+    lock().clear();
+}
+
+/// Returns the marks of the current session, see `Session::marks`.
+pub fn marks() -> Vec<Mark> {
+    ::session::with_current(|session| session.marks())
+}
+
+/// The marks that were made since `from`, relative to `epoch`.
+pub(crate) fn since(from: u64, epoch: u64) -> Vec<Mark> {
+    lock().iter()
+        .filter(|&&(ns, _, _)| ns >= from)
+        .map(|&(ns, thread_id, ref name)| Mark {
+            name: name.clone(),
+            instant: ns.saturating_sub(epoch),
+            thread_id,
+        })
+        .collect()
+}
+
+fn lock() -> ::std::sync::MutexGuard<'static, VecDeque<(u64, usize, StrCow)>> {
+    MARKS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
@@ -0,0 +1,88 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+use super::Session;
+
+const ITERATIONS: u64 = 10_000;
+
+static SPAN_NS: AtomicU64 = AtomicU64::new(0);
+static NOTE_NS: AtomicU64 = AtomicU64::new(0);
+static CORRECTION: AtomicBool = AtomicBool::new(false);
+
+/// The cost of recording on this machine, as measured by `calibrate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Overhead {
+    /// The cost of a `start` and `end` pair, in nanoseconds
+    pub span_ns: u64,
+    /// The cost of a `note`, in nanoseconds
+    pub note_ns: u64,
+}
+
+/// Measures how long recording a span and a note takes on this machine.
+///
+/// The result is stored and used to correct span durations once
+/// `set_overhead_correction(true)` is called.  Measuring takes a few
+/// milliseconds and doesn't record anything into any existing session.
+pub fn calibrate() -> Overhead {
+    let session = Session::new();
+    session.start("calibrate");
+
+    let start = Instant::now();
+    for _ in 0 .. ITERATIONS {
+        session.start("span");
+        session.end("span");
+    }
+    let span_ns = elapsed_ns(start) / ITERATIONS;
+
+    let start = Instant::now();
+    for _ in 0 .. ITERATIONS {
+        session.note("note", None);
+    }
+    let note_ns = elapsed_ns(start) / ITERATIONS;
+
+    session.end("calibrate");
+
+    let overhead = Overhead { span_ns, note_ns };
+    set_overhead(overhead);
+    overhead
+}
+
+/// Overrides the overhead measured by `calibrate`.
+pub fn set_overhead(overhead: Overhead) {
+    SPAN_NS.store(overhead.span_ns, Ordering::Relaxed);
+    NOTE_NS.store(overhead.note_ns, Ordering::Relaxed);
+}
+
+/// Returns the overhead measured by `calibrate`, or zero if it
+/// hasn't been called.
+pub fn overhead() -> Overhead {
+    Overhead {
+        span_ns: SPAN_NS.load(Ordering::Relaxed),
+        note_ns: NOTE_NS.load(Ordering::Relaxed),
+    }
+}
+
+/// Turns the correction of span durations on or off.
+///
+/// When turned on, the `delta` of every span that is converted from
+/// then on has the measured overhead of every span and note recorded
+/// inside of it subtracted.  `raw_delta` always holds the measured
+/// duration.
+pub fn set_overhead_correction(enabled: bool) {
+    CORRECTION.store(enabled, Ordering::Relaxed);
+}
+
+/// The overhead to subtract from spans, or `None` if the correction
+/// is turned off.
+pub(crate) fn correction() -> Option<Overhead> {
+    if CORRECTION.load(Ordering::Relaxed) {
+        Some(overhead())
+    } else {
+        None
+    }
+}
+
+fn elapsed_ns(start: Instant) -> u64 {
+    let elapsed = start.elapsed();
+    elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos())
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const WINDOW_NS: u64 = 1_000_000_000;
+
+/// The sampling rules of a session, shared with the `Sampler` of every
+/// thread.  Threads only look at the rules again when `generation` changes.
+#[derive(Debug, Default)]
+pub(crate) struct SamplingConfig {
+    generation: AtomicUsize,
+    rules: Mutex<Rules>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Rules {
+    every: HashMap<String, u32>,
+    budget: Option<u32>,
+}
+
+impl SamplingConfig {
+    pub(crate) fn sample(&self, name: &str, every: u32) {
+        let mut rules = self.rules.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if every > 1 {
+            rules.every.insert(name.to_owned(), every);
+        } else {
+            rules.every.remove(name);
+        }
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    pub(crate) fn set_budget(&self, budget: Option<u32>) {
+        let mut rules = self.rules.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        rules.budget = budget.map(|budget| budget.max(1));
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    pub(crate) fn clear(&self) {
+        let mut rules = self.rules.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        *rules = Rules::default();
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// The sampling state of one thread.
+///
+/// It is only ever used by its own thread, outside of the lock of the
+/// thread's `Library`, so that skipping a span stays cheap.
+#[derive(Debug)]
+pub(crate) struct Sampler {
+    generation: usize,
+    rules: Rules,
+    names: HashMap<String, NameState, BuildHasherDefault<Fnv>>,
+    window_start_ns: u64,
+}
+
+/// FNV-1a, which is a lot faster than the default hasher for the short
+/// names of spans.  The names aren't chosen by an attacker.
+#[derive(Debug)]
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug)]
+struct NameState {
+    /// Keep one in `every` calls, as configured by `flame::sample`
+    every: u32,
+    /// Keep one in `adaptive` calls, as decided by the budget
+    adaptive: u32,
+    calls: u64,
+    calls_in_window: u64,
+}
+
+impl Sampler {
+    pub(crate) fn new() -> Sampler {
+        Sampler {
+            generation: usize::MAX,
+            rules: Rules::default(),
+            names: HashMap::default(),
+            window_start_ns: 0,
+        }
+    }
+
+    /// Decides whether the next occurrence of `name` is recorded.
+    ///
+    /// Returns the number of occurrences that the recorded one stands
+    /// for, or `None` if it should be skipped.  `now` is only called
+    /// for occurrences that are recorded.
+    pub(crate) fn sample<F>(&mut self, config: &SamplingConfig, name: &str, now: F) -> Option<u32>
+    where F: FnOnce() -> u64 {
+        let generation = config.generation.load(Ordering::Acquire);
+        if generation != self.generation {
+            self.reload(config, generation);
+        }
+        if self.rules.every.is_empty() && self.rules.budget.is_none() {
+            return Some(1);
+        }
+
+        let weight = {
+            let state = match self.names.get_mut(name) {
+                Some(state) => state,
+                None if self.rules.budget.is_some() => self.names.entry(name.to_owned()).or_insert(NameState {
+                    every: 1,
+                    adaptive: 1,
+                    calls: 0,
+                    calls_in_window: 0,
+                }),
+                None => return Some(1),
+            };
+            state.calls += 1;
+            state.calls_in_window += 1;
+            let every = state.every.max(state.adaptive);
+            if (state.calls - 1) % u64::from(every) != 0 {
+                return None;
+            }
+            every
+        };
+
+        if let Some(budget) = self.rules.budget {
+            let now = now();
+            if now.saturating_sub(self.window_start_ns) >= WINDOW_NS {
+                let seconds = (now.saturating_sub(self.window_start_ns) / WINDOW_NS).max(1);
+                for state in self.names.values_mut() {
+                    let rate = state.calls_in_window / seconds;
+                    state.adaptive = ((rate + u64::from(budget) - 1) / u64::from(budget)).max(1) as u32;
+                    state.calls_in_window = 0;
+                }
+                self.window_start_ns = now;
+            }
+        }
+        Some(weight)
+    }
+
+    fn reload(&mut self, config: &SamplingConfig, generation: usize) {
+        self.rules = config.rules.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+        self.generation = generation;
+        for state in self.names.values_mut() {
+            state.every = 1;
+        }
+        for (name, &every) in &self.rules.every {
+            self.names.entry(name.clone()).or_insert(NameState {
+                every: 1,
+                adaptive: 1,
+                calls: 0,
+                calls_in_window: 0,
+            }).every = every;
+        }
+        if self.rules.budget.is_none() {
+            for state in self.names.values_mut() {
+                state.adaptive = 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+//! A tiny HTTP server that shows what a running process recorded.
+//!
+//! It only depends on std, understands just enough HTTP/1.1 for a
+//! browser and handles one request at a time.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use super::{Filter, Session, SnapshotOptions, Thread};
+
+const MAX_REQUEST: usize = 8 * 1024;
+
+const INDEX: &str = r#"<!doctype html>
+<html>
+    <head><meta charset="utf-8"><title>flame</title></head>
+    <body>
+        <ul>
+            <li><a href="/flamegraph">flamegraph</a></li>
+            <li><a href="/timeline">timeline</a></li>
+            <li><a href="/json">json</a></li>
+        </ul>
+        <p>
+            Every page takes the query parameters <code>thread</code> (a name or id),
+            <code>from</code> and <code>to</code> (nanoseconds since the epoch of the
+            session) and <code>min</code> (the minimum duration of a span in nanoseconds).
+        </p>
+    </body>
+</html>"#;
+
+/// Serves the spans of the calling thread's current session over HTTP
+/// on `addr`, for example `"127.0.0.1:7878"`.
+///
+/// Every request takes a new snapshot that includes open spans:
+///
+/// * `/flamegraph` is the page from `dump_html`
+/// * `/timeline` is the page from `dump_timeline`
+/// * `/json` is the data from `dump_json` (with the `json` feature)
+///
+/// Each takes the query parameters `thread` (a name or id), `from` and
+/// `to` (nanoseconds since the epoch of the session) and `min` (the
+/// minimum duration of a span in nanoseconds), see `Filter`.
+///
+/// The server runs on a background thread until the process exits.
+/// Returns the address that it listens on, which is useful when `addr`
+/// asks for port 0.
+pub fn serve(addr: &str) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let session = Session::current();
+    thread::Builder::new()
+        .name("flame serve".into())
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = handle(stream, &session);
+            }
+        })?;
+    Ok(local_addr)
+}
+
+fn handle(mut stream: TcpStream, session: &Session) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buf)?;
+        if read == 0 || request.len() + read > MAX_REQUEST {
+            return respond(&mut stream, "400 Bad Request", "text/plain", b"bad request");
+        }
+        request.extend_from_slice(&buf[.. read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut words = request.split_whitespace();
+    let (method, target) = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return respond(&mut stream, "400 Bad Request", "text/plain", b"bad request"),
+    };
+    if method != "GET" {
+        return respond(&mut stream, "405 Method Not Allowed", "text/plain", b"only GET is supported");
+    }
+
+    let (path, query) = match target.find('?') {
+        Some(split) => (&target[.. split], &target[split + 1 ..]),
+        None => (target, ""),
+    };
+    let filter = match parse_filter(query) {
+        Ok(filter) => filter,
+        Err(message) => return respond(&mut stream, "400 Bad Request", "text/plain", message.as_bytes()),
+    };
+
+    let own_id = ::thread_id::get();
+    let threads = || -> Vec<Thread> {
+        let mut threads = session.threads_with(&SnapshotOptions::new().include_open(true));
+        threads.retain(|thread| thread.id != own_id);
+        filter.threads(&threads)
+    };
+
+    let mut body = vec![];
+    match path {
+        "/" => body.extend_from_slice(INDEX.as_bytes()),
+        "/flamegraph" => ::html::dump_html_threads(&mut body, &threads())?,
+        "/timeline" => ::timeline::dump_timeline_custom(&mut body, &threads())?,
+        #[cfg(feature = "json")]
+        "/json" => {
+            body = ::serde_json::to_vec_pretty(&threads()).map_err(io::Error::from)?;
+            return respond(&mut stream, "200 OK", "application/json", &body);
+        }
+        _ => return respond(&mut stream, "404 Not Found", "text/plain", b"not found"),
+    }
+    respond(&mut stream, "200 OK", "text/html; charset=utf-8", &body)
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+           status, content_type, body.len())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+fn parse_filter(query: &str) -> Result<Filter, String> {
+    let mut filter = Filter::new();
+    let mut from = None;
+    let mut to = None;
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = match pair.find('=') {
+            Some(split) => (&pair[.. split], decode(&pair[split + 1 ..])),
+            None => (pair, String::new()),
+        };
+        let number = || value.parse::<u64>().map_err(|_| format!("{} must be a number of nanoseconds", key));
+        match key {
+            "thread" => filter = filter.thread(value.clone()),
+            "from" => from = Some(number()?),
+            "to" => to = Some(number()?),
+            "min" => filter = filter.min_duration(number()?),
+            _ => return Err(format!("unknown query parameter {}", key)),
+        }
+    }
+    if from.is_some() || to.is_some() {
+        filter = filter.window(from.unwrap_or(0), to.unwrap_or(u64::MAX));
+    }
+    Ok(filter)
+}
+
+/// Decodes `+` and `%XX` escapes in a query value.
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match value.get(i + 1 .. i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,554 @@
+use std::cell::RefCell;
+use std::io::{Write, Result as IoResult};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::{Library, CommittedFrame, Span, SpanId, Thread, SnapshotOptions, SpanGuard, StrCow, Location, FrameProfile};
+use clock;
+use frames::{self, DEFAULT_FRAME_HISTORY};
+use html::HtmlOptions;
+use marks::{self, Mark};
+use sampling::{Sampler, SamplingConfig};
+use text::TextReportOptions;
+use super::{start_event, end_event, note_event, begin_event, finish_event, take_frame};
+use super::{library_spans, library_async_spans, convert_events_to_span, convert_detached_events};
+
+lazy_static!(static ref GLOBAL: Session = Session::new(););
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+// The libraries of every session that this thread has recorded into.
+thread_local!(static LIBRARIES: RefCell<Vec<LocalLibrary>> = const { RefCell::new(Vec::new()) });
+// The sessions installed by `with_session`, innermost last.
+thread_local!(static CURRENT: RefCell<Vec<Session>> = const { RefCell::new(Vec::new()) });
+
+/// An independent profiling session.
+///
+/// A session owns the recordings of every thread that records into it,
+/// along with the epoch that all of their timestamps are relative to.
+/// The free functions like `flame::start` and `flame::threads` use a
+/// global session unless another one is installed with `with_session`.
+///
+/// ```
+/// let session = flame::Session::new();
+/// session.start("work");
+/// session.end("work");
+///
+/// // The free functions record into `session` inside of the closure.
+/// flame::with_session(&session, || flame::span_of("more work", || ()));
+///
+/// assert_eq!(session.spans().len(), 2);
+/// ```
+#[derive(Clone)]
+pub struct Session {
+    inner: Arc<SessionInner>,
+}
+
+struct SessionInner {
+    id: usize,
+    epoch: u64,
+    /// The clock time of the last `clear`, older marks are left out.
+    marks_from: AtomicU64,
+    /// The library of every thread that recorded into this session, so
+    /// that any thread can take a snapshot of the others while they are
+    /// running.  Frames are only moved into `committed` while this lock
+    /// is held, so a snapshot never sees a frame twice.
+    live: Mutex<Vec<Weak<Mutex<Library>>>>,
+    committed: Mutex<Vec<CommittedFrame>>,
+    frame_history: AtomicUsize,
+    sampling: Arc<SamplingConfig>,
+}
+
+/// A thread's handle on its `Library` for one session.  The recorded
+/// frame is committed to the session when the thread exits.
+struct LocalLibrary {
+    session: Weak<SessionInner>,
+    session_id: usize,
+    library: Arc<Mutex<Library>>,
+    /// Sampling is decided here rather than in `library`, so that
+    /// skipping a span doesn't take the lock.
+    sampler: Sampler,
+    /// How deep the thread is inside of a span that was skipped by
+    /// sampling, everything in there is skipped as well
+    unsampled: u32,
+}
+
+impl LocalLibrary {
+    /// Decides whether the thread records the span named `name` that it
+    /// is about to start, and returns its weight if it does.
+    ///
+    /// Skipped spans are only counted, without locking the library.
+    fn sample_next(&mut self, sampling: &SamplingConfig, name: &str, epoch: u64) -> Option<u32> {
+        if self.unsampled > 0 {
+            self.unsampled += 1;
+            return None;
+        }
+        let weight = self.sampler.sample(sampling, name, || clock::now_ns().saturating_sub(epoch));
+        if weight.is_none() {
+            self.unsampled = 1;
+        }
+        weight
+    }
+
+    /// Ends a span that was skipped by sampling, returns `false` if the
+    /// thread isn't inside of one.
+    fn end_unsampled(&mut self) -> bool {
+        let unsampled = self.unsampled > 0;
+        if unsampled {
+            self.unsampled -= 1;
+        }
+        unsampled
+    }
+}
+
+impl Drop for LocalLibrary {
+    fn drop(&mut self) {
+        if ::std::thread::panicking() { return; }
+        if let Some(session) = self.session.upgrade() {
+            let _live = lock(&session.live);
+            commit(&session, &mut lock(&self.library));
+        }
+    }
+}
+
+fn lock<'a, T>(mutex: &'a Mutex<T>) -> MutexGuard<'a, T> {
+    // A panic while recording (e.g. a mismatched `end`) poisons the
+    // lock, but never leaves the library in an inconsistent state.
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Like `lock`, but returns `None` instead of blocking.
+fn try_lock<'a, T>(mutex: &'a Mutex<T>) -> Option<MutexGuard<'a, T>> {
+    match mutex.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+fn commit(session: &SessionInner, library: &mut Library) {
+    if let Some(frame) = take_frame(library) {
+        lock(&session.committed).push(frame);
+    }
+}
+
+/// Runs `f` with the free functions of this crate (`start`, `end`,
+/// `spans`, `dump_html`, ...) recording into `session` on the calling
+/// thread.
+pub fn with_session<F, R>(session: &Session, f: F) -> R where F: FnOnce() -> R {
+    struct Restore;
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT.with(|current| current.borrow_mut().pop());
+        }
+    }
+
+    CURRENT.with(|current| current.borrow_mut().push(session.clone()));
+    let _restore = Restore;
+    f()
+}
+
+/// Calls `f` with the session that the free functions record into.
+pub(crate) fn with_current<F, R>(f: F) -> R where F: FnOnce(&Session) -> R {
+    CURRENT.with(|current| match current.borrow().last() {
+        Some(session) => f(session),
+        None => f(&GLOBAL),
+    })
+}
+
+/// Calls `f` on every library of the calling thread and commits them.
+///
+/// This is used by the panic hook, so it skips libraries (and sessions)
+/// that are currently in use instead of blocking on them, as the panic
+/// might have been raised while this thread was using them.
+pub(crate) fn commit_all_for_panic<F>(mut f: F) where F: FnMut(&mut Library) -> bool {
+    let _ = LIBRARIES.try_with(|libraries| {
+        let mut libraries = match libraries.try_borrow_mut() {
+            Ok(libraries) => libraries,
+            Err(_) => return,
+        };
+        for local in libraries.iter_mut() {
+            // The panic ends the skipped spans along with the others.
+            local.unsampled = 0;
+            let session = match local.session.upgrade() {
+                Some(session) => session,
+                None => continue,
+            };
+            let _live = match try_lock(&session.live) {
+                Some(live) => live,
+                None => continue,
+            };
+            let mut library = match try_lock(&local.library) {
+                Some(library) => library,
+                None => continue,
+            };
+            if f(&mut library) {
+                commit(&session, &mut library);
+            }
+        }
+    });
+}
+
+impl Default for Session {
+    fn default() -> Session {
+        Session::new()
+    }
+}
+
+impl Session {
+    /// Creates an empty session whose epoch is now.
+    pub fn new() -> Session {
+        let epoch = clock::now_ns();
+        Session {
+            inner: Arc::new(SessionInner {
+                id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+                epoch,
+                marks_from: AtomicU64::new(epoch),
+                live: Mutex::new(Vec::new()),
+                committed: Mutex::new(Vec::new()),
+                frame_history: AtomicUsize::new(DEFAULT_FRAME_HISTORY),
+                sampling: Arc::new(SamplingConfig::default()),
+            }),
+        }
+    }
+
+    /// Returns the session that the free functions record into on
+    /// the calling thread.
+    pub fn current() -> Session {
+        with_current(Session::clone)
+    }
+
+    /// Calls `f` with the calling thread's handle on this session,
+    /// creating it if this thread hasn't recorded into it yet.
+    fn with_local<F, R>(&self, f: F) -> R where F: FnOnce(&mut LocalLibrary) -> R {
+        LIBRARIES.with(|libraries| {
+            let mut libraries = libraries.borrow_mut();
+            let index = match libraries.iter().position(|local| local.session_id == self.inner.id) {
+                Some(index) => index,
+                None => {
+                    let library = Arc::new(Mutex::new(Library::new(self.inner.epoch)));
+                    {
+                        let mut live = lock(&self.inner.live);
+                        live.retain(|weak| weak.upgrade().is_some());
+                        live.push(Arc::downgrade(&library));
+                    }
+                    // Forget about the libraries of sessions that no longer exist.
+                    libraries.retain(|local| local.session.upgrade().is_some());
+                    libraries.push(LocalLibrary {
+                        session: Arc::downgrade(&self.inner),
+                        session_id: self.inner.id,
+                        library,
+                        sampler: Sampler::new(),
+                        unsampled: 0,
+                    });
+                    libraries.len() - 1
+                }
+            };
+            f(&mut libraries[index])
+        })
+    }
+
+    /// Returns the calling thread's library for this session.
+    fn local_library(&self) -> Arc<Mutex<Library>> {
+        self.with_local(|local| local.library.clone())
+    }
+
+    /// Ends a span that was skipped by sampling, returns `false` if the
+    /// calling thread isn't inside of one.
+    pub(crate) fn end_unsampled(&self) -> bool {
+        self.with_local(LocalLibrary::end_unsampled)
+    }
+
+    /// Calls `f` with the calling thread's library for this session.
+    /// `f` must not record anything itself, the thread's handles are
+    /// borrowed while it runs.
+    pub(crate) fn with_library<F, R>(&self, f: F) -> R where F: FnOnce(&mut Library) -> R {
+        self.with_local(|local| f(&mut lock(&local.library)))
+    }
+
+    /// Starts a new Span in this session.
+    pub fn start<S: Into<StrCow>>(&self, name: S) {
+        self.start_at(name.into(), None);
+    }
+
+    /// Starts a span unless sampling skips it, and returns its id.
+    pub(crate) fn start_at(&self, name: StrCow, location: Option<Location>) -> Option<u32> {
+        let epoch = self.inner.epoch;
+        let sampling = &self.inner.sampling;
+        self.with_local(|local| {
+            let weight = local.sample_next(sampling, &name, epoch)?;
+            Some(start_event(&mut lock(&local.library), name, location, weight))
+        })
+    }
+
+    /// Starts a Span in this session and returns a `SpanGuard` that
+    /// ends it when dropped.
+    pub fn start_guard<S: Into<StrCow>>(&self, name: S) -> SpanGuard {
+        let name = name.into();
+        self.start(name.clone());
+        SpanGuard { name: Some(name), collapse: false, session: self.clone() }
+    }
+
+    /// Ends the current Span of this session and returns the number
+    /// of nanoseconds that passed.
+    pub fn end<S: Into<StrCow>>(&self, name: S) -> u64 {
+        self.end_impl(name.into(), false)
+    }
+
+    pub(crate) fn end_impl(&self, name: StrCow, collapse: bool) -> u64 {
+        let ended = self.with_local(|local| {
+            if local.end_unsampled() {
+                return Ok(Some(0));
+            }
+            end_event(&mut lock(&local.library), &name, collapse)
+        });
+        // Errors are raised once the library is unlocked again, so
+        // that the panic hook can still record the panic.
+        match ended {
+            Ok(delta) => delta.unwrap_or_default(), // `None` when panicking
+            Err(message) => panic!("{}", message),
+        }
+    }
+
+    /// Starts a span in this session that can overlap other spans, see
+    /// `flame::begin`.
+    pub fn begin<S: Into<StrCow>>(&self, name: S) -> SpanId {
+        let name = name.into();
+        self.with_library(|library| begin_event(library, name))
+    }
+
+    /// Ends a span that was started with `begin`, see `flame::finish`.
+    pub fn finish(&self, id: SpanId) -> u64 {
+        match self.with_library(|library| finish_event(library, id)) {
+            Ok(delta) => delta,
+            Err(message) => panic!("{}", message),
+        }
+    }
+
+    /// Records a note on the current Span of this session, or on the
+    /// calling thread if no span is running.
+    pub fn note<S: Into<StrCow>>(&self, name: S, description: Option<S>) {
+        self.note_at(name.into(), description.map(Into::into), None);
+    }
+
+    pub(crate) fn note_at(&self, name: StrCow, description: Option<StrCow>, location: Option<Location>) {
+        self.note_with(name, description, location, vec![]);
+    }
+
+    /// Records a note with attributes, unless the calling thread is inside
+    /// of a span that was skipped by sampling.
+    pub(crate) fn note_with(&self, name: StrCow, description: Option<StrCow>, location: Option<Location>,
+                            attributes: Vec<(String, String)>) {
+        self.with_local(|local| {
+            if local.unsampled == 0 {
+                note_event(&mut lock(&local.library), name, description, location, attributes);
+            }
+        });
+    }
+
+    /// Moves everything that the calling thread recorded into this
+    /// session so far into the session's list of finished threads.
+    pub fn commit_thread(&self) {
+        let library = self.local_library();
+        let _live = lock(&self.inner.live);
+        commit(&self.inner, &mut lock(&library));
+    }
+
+    /// Clears everything that was recorded into this session, by running
+    /// threads as well as finished ones.
+    ///
+    /// The spans that other threads are still running are kept, so that
+    /// they can end them.  Marks that were made so far are left out of
+    /// the session from now on, other sessions still see them.
+    pub fn clear(&self) {
+        if ::std::thread::panicking() { return; }
+        let current = self.with_local(|local| {
+            local.unsampled = 0;
+            local.library.clone()
+        });
+        let live = lock(&self.inner.live);
+        for library in live.iter().filter_map(Weak::upgrade) {
+            if Arc::ptr_eq(&library, &current) {
+                lock(&library).clear();
+            } else {
+                lock(&library).clear_finished();
+            }
+        }
+        lock(&self.inner.committed).clear();
+        self.inner.marks_from.store(clock::now_ns(), Ordering::Relaxed);
+    }
+
+    /// Ends the calling thread's current frame in this session, see
+    /// `flame::frame_mark`.
+    pub fn frame_mark(&self) {
+        let history = self.inner.frame_history.load(Ordering::Relaxed);
+        self.with_library(|library| frames::mark_frame(library, history));
+    }
+
+    /// Returns the frames that the calling thread recorded into this
+    /// session, oldest first.
+    pub fn frames(&self) -> Vec<FrameProfile> {
+        self.with_library(|library| library.frames.frames.iter().cloned().collect())
+    }
+
+    /// Sets how many frames are kept per thread.
+    pub fn set_frame_history(&self, frames: usize) {
+        self.inner.frame_history.store(frames, Ordering::Relaxed);
+    }
+
+    /// Only records one in `every` occurrences of spans named `name`
+    /// in this session, see `flame::sample`.
+    pub fn sample<S: AsRef<str>>(&self, name: S, every: u32) {
+        self.inner.sampling.sample(name.as_ref(), every);
+    }
+
+    /// Limits how often each span name is recorded per thread in this
+    /// session, see `flame::sample_adaptive`.
+    pub fn sample_adaptive(&self, events_per_second: Option<u32>) {
+        self.inner.sampling.set_budget(events_per_second);
+    }
+
+    /// Removes all sampling rules of this session.
+    pub fn clear_sampling(&self) {
+        self.inner.sampling.clear();
+    }
+
+    /// Returns a list of spans that the calling thread recorded
+    /// into this session.
+    pub fn spans(&self) -> Vec<Span> {
+        self.spans_with(&SnapshotOptions::default())
+    }
+
+    /// Like `spans`, but filtered according to `options`.
+    pub fn spans_with(&self, options: &SnapshotOptions) -> Vec<Span> {
+        if ::std::thread::panicking() { return vec![]; }
+        self.with_library(|library| library_spans(library, options))
+    }
+
+    /// Returns a snapshot of every thread that recorded into this session.
+    ///
+    /// The calling thread always comes first, followed by threads that are
+    /// still running and finally threads that have exited or called
+    /// `commit_thread`.
+    pub fn threads(&self) -> Vec<Thread> {
+        self.threads_with(&SnapshotOptions::default())
+    }
+
+    /// Like `threads`, but the spans are filtered according to `options`.
+    pub fn threads_with(&self, options: &SnapshotOptions) -> Vec<Thread> {
+        if ::std::thread::panicking() { return vec![]; }
+
+        let current = self.local_library();
+        let live = lock(&self.inner.live);
+
+        let mut out = vec![];
+        {
+            let library = lock(&current);
+            out.push(Thread {
+                id: library.id,
+                name: library.name.clone(),
+                spans: library_spans(&library, options),
+                async_spans: library_async_spans(&library, options),
+                notes: library.current.notes.clone(),
+                parent: library.current.parent.clone(),
+                frame_marks: library.current.frame_marks.clone(),
+                marks: vec![],
+            });
+        }
+
+        for library in live.iter().filter_map(Weak::upgrade) {
+            if Arc::ptr_eq(&library, &current) {
+                continue;
+            }
+            let library = lock(&library);
+            if library.current.is_empty() {
+                continue;
+            }
+            out.push(Thread {
+                id: library.id,
+                name: library.name.clone(),
+                spans: library_spans(&library, options),
+                async_spans: library_async_spans(&library, options),
+                notes: library.current.notes.clone(),
+                parent: library.current.parent.clone(),
+                frame_marks: library.current.frame_marks.clone(),
+                marks: vec![],
+            });
+        }
+
+        for committed in lock(&self.inner.committed).iter() {
+            out.push(Thread {
+                id: committed.thread_id,
+                name: committed.thread_name.clone(),
+                spans: convert_events_to_span(committed.frame.all.iter(), options, committed.committed_ns),
+                async_spans: convert_detached_events(committed.frame.detached.iter(), options, committed.committed_ns),
+                notes: committed.frame.notes.clone(),
+                parent: committed.frame.parent.clone(),
+                frame_marks: committed.frame.frame_marks.clone(),
+                marks: vec![],
+            });
+        }
+
+        // Marks go to the thread that made them, or to the first one if
+        // that thread isn't part of the session.
+        for mark in self.marks() {
+            let index = out.iter().position(|thread| thread.id == mark.thread_id).unwrap_or(0);
+            out[index].marks.push(mark);
+        }
+        out
+    }
+
+    /// Writes a flamegraph of every thread of this session.
+    pub fn dump_html<W: Write>(&self, out: W) -> IoResult<()> {
+        self.dump_html_with(out, &HtmlOptions::default())
+    }
+
+    pub fn dump_html_with<W: Write>(&self, out: W, options: &HtmlOptions) -> IoResult<()> {
+        ::html::dump_html_threads_with(out, &self.threads_with(&options.snapshot()), options)
+    }
+
+    pub fn dump_timeline<W: Write>(&self, out: W) -> IoResult<()> {
+        let threads = self.threads_with(&SnapshotOptions::new().include_open(true));
+        ::timeline::dump_timeline_custom(out, &threads)
+    }
+
+    /// The marks that were made since this session was created or last
+    /// cleared, see `flame::mark`.
+    pub fn marks(&self) -> Vec<Mark> {
+        marks::since(self.inner.marks_from.load(Ordering::Relaxed), self.inner.epoch)
+    }
+
+    #[cfg(feature = "json")]
+    pub fn dump_json<W: Write>(&self, out: &mut W) -> IoResult<()> {
+        self.dump_json_with(out, &SnapshotOptions::default())
+    }
+
+    /// Writes the threads that `threads_with(options)` returns as JSON.
+    #[cfg(feature = "json")]
+    pub fn dump_json_with<W: Write>(&self, out: &mut W, options: &SnapshotOptions) -> IoResult<()> {
+        let threads = self.threads_with(options);
+        out.write_all(::serde_json::to_string_pretty(&threads).unwrap().as_bytes())
+    }
+
+    /// Writes a plain text report, see `dump_text_with` for one with
+    /// colors, bars and percentages.
+    pub fn dump_text_to_writer<W: Write>(&self, out: W) -> IoResult<()> {
+        ::text::write_plain(out, &self.threads())
+    }
+
+    pub fn dump_text_with<W: Write>(&self, out: W, options: &TextReportOptions) -> IoResult<()> {
+        ::text::write_report(out, &self.threads_with(&options.snapshot()), options, false)
+    }
+
+    pub fn dump_stdout(&self) {
+        let stdout = ::std::io::stdout();
+        let stdout = stdout.lock();
+        let _ = self.dump_text_to_writer(stdout);
+    }
+
+    pub fn dump_stdout_with(&self, options: &TextReportOptions) {
+        let threads = self.threads_with(&options.snapshot());
+        let stdout = ::std::io::stdout();
+        let stdout = stdout.lock();
+        let _ = ::text::write_report(stdout, &threads, options, ::text::stdout_is_terminal());
+    }
+}
@@ -0,0 +1,426 @@
+//! Streaming events to another process while they happen.
+//!
+//! # Protocol
+//!
+//! A stream starts with the 6 byte header `FLAME\x01`, the last byte
+//! being the protocol version.  Every event after it is one frame:
+//!
+//! ```text
+//! frame   = length:u32 kind:u8 thread_id:u64 timestamp_ns:u64 name:string body
+//! body    = location?               (kind 1, a span started)
+//!         | ()                      (kind 2, a span ended)
+//!         | string? location?       (kind 3, a note)
+//!         | id:u32                  (kind 4, a span started with `begin`)
+//!         | id:u32                  (kind 5, a span ended with `finish`)
+//! string  = length:u32 utf8 bytes
+//! T?      = 0:u8 | 1:u8 T
+//! location = file:string line:u32 module_path:string
+//! ```
+//!
+//! Integers are big endian and `length` counts the bytes after itself.
+//! Timestamps are nanoseconds since the starting point that all clocks
+//! share (see `flame::clock::anchor_ns`), so events of different sessions
+//! can be compared.  Readers should skip the rest of frames of unknown
+//! kinds.
+
+use std::cell::RefCell;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use super::{Location, StrCow};
+
+const HEADER: &[u8] = b"FLAME\x01";
+
+const START: u8 = 1;
+const END: u8 = 2;
+const NOTE: u8 = 3;
+const BEGIN: u8 = 4;
+const FINISH: u8 = 5;
+
+/// Frames longer than this are treated as garbage.
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+/// An event that was streamed by `stream_to`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LiveEvent {
+    /// A span started
+    Start {
+        thread_id: u64,
+        timestamp_ns: u64,
+        name: String,
+        location: Option<Location>,
+    },
+    /// A span ended
+    End {
+        thread_id: u64,
+        timestamp_ns: u64,
+        name: String,
+    },
+    /// A note was recorded
+    Note {
+        thread_id: u64,
+        timestamp_ns: u64,
+        name: String,
+        description: Option<String>,
+        location: Option<Location>,
+    },
+    /// A span that can overlap other spans started, see `flame::begin`
+    Begin {
+        thread_id: u64,
+        timestamp_ns: u64,
+        name: String,
+        /// Tells the span apart from the other running spans of the
+        /// thread, the `Finish` event of the span has the same id
+        id: u32,
+    },
+    /// A span that was started with `flame::begin` ended
+    Finish {
+        thread_id: u64,
+        timestamp_ns: u64,
+        name: String,
+        id: u32,
+    },
+}
+
+impl LiveEvent {
+    /// Reads the next event from a stream whose header was already read,
+    /// skipping frames of unknown kinds.
+    ///
+    /// Malformed frames are reported as `io::ErrorKind::InvalidData`.
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<LiveEvent> {
+        read_event(input)
+    }
+}
+
+enum Message {
+    Event(LiveEvent),
+    Stop,
+}
+
+lazy_static! {
+    static ref STREAM: Mutex<Option<Sender<Message>>> = Mutex::new(None);
+}
+
+/// The generation of the current stream, or 0 if nothing is streamed.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+static NEXT_GENERATION: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    static SENDER: RefCell<Option<(usize, Sender<Message>)>> = const { RefCell::new(None) };
+}
+
+/// Starts forwarding the span and note events of every thread and every
+/// session to `addr` as they happen.
+///
+/// `addr` is either a TCP address like `"127.0.0.1:7879"` or, on Unix,
+/// the path of a Unix socket prefixed with `unix:`.  The events are
+/// written by a background thread, streaming stops on the first error.
+/// Events that haven't been written when the process exits are lost,
+/// call `stop_streaming` first to flush them.
+///
+/// Calling this again replaces the previous stream.
+pub fn stream_to(addr: &str) -> io::Result<()> {
+    let out: Box<dyn Write + Send> = if let Some(path) = addr.strip_prefix("unix:") {
+        connect_unix(path)?
+    } else {
+        Box::new(TcpStream::connect(addr)?)
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+    thread::Builder::new()
+        .name("flame stream".into())
+        .spawn(move || {
+            if write_events(out, receiver).is_err() {
+                let _ = ACTIVE.compare_exchange(generation, 0, Ordering::AcqRel, Ordering::Relaxed);
+            }
+        })?;
+
+    let mut stream = STREAM.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(previous) = stream.replace(sender) {
+        let _ = previous.send(Message::Stop);
+    }
+    ACTIVE.store(generation, Ordering::Release);
+    Ok(())
+}
+
+/// Stops the stream started by `stream_to` after writing every event
+/// that was sent so far.
+pub fn stop_streaming() {
+    let mut stream = STREAM.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    ACTIVE.store(0, Ordering::Release);
+    if let Some(sender) = stream.take() {
+        let _ = sender.send(Message::Stop);
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> io::Result<Box<dyn Write + Send>> {
+    Ok(Box::new(UnixStream::connect(path)?))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &str) -> io::Result<Box<dyn Write + Send>> {
+    Err(io::Error::new(io::ErrorKind::Other, "Unix sockets aren't supported on this platform"))
+}
+
+fn write_events(out: Box<dyn Write + Send>, receiver: Receiver<Message>) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+    out.write_all(HEADER)?;
+    out.flush()?;
+
+    let mut frame = vec![];
+    loop {
+        // Block for the next event, but only flush once there is
+        // nothing left to write.
+        let message = match receiver.try_recv() {
+            Ok(message) => message,
+            Err(TryRecvError::Empty) => {
+                out.flush()?;
+                match receiver.recv() {
+                    Ok(message) => message,
+                    Err(_) => return Ok(()),
+                }
+            }
+            Err(TryRecvError::Disconnected) => break,
+        };
+        match message {
+            Message::Event(event) => {
+                frame.clear();
+                encode(&event, &mut frame);
+                out.write_all(&(frame.len() as u32).to_be_bytes())?;
+                out.write_all(&frame)?;
+            }
+            Message::Stop => break,
+        }
+    }
+    out.flush()
+}
+
+/// Hands the event built by `event` to the stream, if there is one.
+pub(crate) fn emit<F: FnOnce() -> LiveEvent>(event: F) {
+    let generation = ACTIVE.load(Ordering::Acquire);
+    if generation == 0 {
+        return;
+    }
+    let _ = SENDER.try_with(|cached| {
+        let mut cached = cached.borrow_mut();
+        let stale = match *cached {
+            Some((cached_generation, _)) => cached_generation != generation,
+            None => true,
+        };
+        if stale {
+            let stream = STREAM.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            *cached = stream.as_ref().map(|sender| (generation, sender.clone()));
+        }
+        if let Some((_, ref sender)) = *cached {
+            let _ = sender.send(Message::Event(event()));
+        }
+    });
+}
+
+fn encode(event: &LiveEvent, out: &mut Vec<u8>) {
+    match *event {
+        LiveEvent::Start { thread_id, timestamp_ns, ref name, ref location } => {
+            encode_head(START, thread_id, timestamp_ns, name, out);
+            encode_location(location, out);
+        }
+        LiveEvent::End { thread_id, timestamp_ns, ref name } => {
+            encode_head(END, thread_id, timestamp_ns, name, out);
+        }
+        LiveEvent::Begin { thread_id, timestamp_ns, ref name, id } => {
+            encode_head(BEGIN, thread_id, timestamp_ns, name, out);
+            out.extend_from_slice(&id.to_be_bytes());
+        }
+        LiveEvent::Finish { thread_id, timestamp_ns, ref name, id } => {
+            encode_head(FINISH, thread_id, timestamp_ns, name, out);
+            out.extend_from_slice(&id.to_be_bytes());
+        }
+        LiveEvent::Note { thread_id, timestamp_ns, ref name, ref description, ref location } => {
+            encode_head(NOTE, thread_id, timestamp_ns, name, out);
+            match *description {
+                Some(ref description) => {
+                    out.push(1);
+                    encode_str(description, out);
+                }
+                None => out.push(0),
+            }
+            encode_location(location, out);
+        }
+    }
+}
+
+fn encode_head(kind: u8, thread_id: u64, timestamp_ns: u64, name: &str, out: &mut Vec<u8>) {
+    out.push(kind);
+    out.extend_from_slice(&thread_id.to_be_bytes());
+    out.extend_from_slice(&timestamp_ns.to_be_bytes());
+    encode_str(name, out);
+}
+
+fn encode_str(s: &str, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn encode_location(location: &Option<Location>, out: &mut Vec<u8>) {
+    match *location {
+        Some(ref location) => {
+            out.push(1);
+            encode_str(&location.file, out);
+            out.extend_from_slice(&location.line.to_be_bytes());
+            encode_str(&location.module_path, out);
+        }
+        None => out.push(0),
+    }
+}
+
+/// Something that `receive` can accept a stream from.
+pub trait Listener {
+    type Stream: Read;
+
+    fn accept_stream(&self) -> io::Result<Self::Stream>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_stream(&self) -> io::Result<TcpStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept_stream(&self) -> io::Result<UnixStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+}
+
+/// Accepts one connection from `stream_to` on `listener` and returns
+/// its events.
+///
+/// The connection is only accepted once the first event is asked for.
+/// The iterator ends when the sender disconnects or sends something
+/// that isn't a valid stream.
+pub fn receive<L: Listener>(listener: L) -> impl Iterator<Item = LiveEvent> {
+    let mut reader: Option<BufReader<L::Stream>> = None;
+    let mut done = false;
+    ::std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        if reader.is_none() {
+            reader = accept(&listener);
+        }
+        let event = reader.as_mut().and_then(|reader| read_event(reader).ok());
+        done = event.is_none();
+        event
+    })
+}
+
+fn accept<L: Listener>(listener: &L) -> Option<BufReader<L::Stream>> {
+    let mut reader = BufReader::new(listener.accept_stream().ok()?);
+    let mut header = [0; 6];
+    reader.read_exact(&mut header).ok()?;
+    if header != HEADER {
+        return None;
+    }
+    Some(reader)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads frames until one of a known kind comes along.
+fn read_event<R: Read>(input: &mut R) -> io::Result<LiveEvent> {
+    loop {
+        let length = read_u32(input)? as usize;
+        if length > MAX_FRAME {
+            return Err(invalid("frame is too long"));
+        }
+        let mut frame = vec![0; length];
+        input.read_exact(&mut frame)?;
+
+        let mut frame = &frame[..];
+        let kind = read_u8(&mut frame)?;
+        if !(START..=FINISH).contains(&kind) {
+            continue;
+        }
+        let thread_id = read_u64(&mut frame)?;
+        let timestamp_ns = read_u64(&mut frame)?;
+        let name = read_string(&mut frame)?;
+        return Ok(match kind {
+            START => LiveEvent::Start {
+                thread_id,
+                timestamp_ns,
+                name,
+                location: read_location(&mut frame)?,
+            },
+            END => LiveEvent::End { thread_id, timestamp_ns, name },
+            BEGIN => LiveEvent::Begin { thread_id, timestamp_ns, name, id: read_u32(&mut frame)? },
+            FINISH => LiveEvent::Finish { thread_id, timestamp_ns, name, id: read_u32(&mut frame)? },
+            _ => {
+                let description = match read_u8(&mut frame)? {
+                    0 => None,
+                    _ => Some(read_string(&mut frame)?),
+                };
+                LiveEvent::Note {
+                    thread_id,
+                    timestamp_ns,
+                    name,
+                    description,
+                    location: read_location(&mut frame)?,
+                }
+            }
+        });
+    }
+}
+
+fn read_u8<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    input.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Strings are read from a frame that was already read, so that a
+/// garbage length can't make us allocate more than the frame.
+fn read_string(input: &mut &[u8]) -> io::Result<String> {
+    let length = read_u32(input)? as usize;
+    if length > input.len() {
+        return Err(invalid("string is longer than its frame"));
+    }
+    let (string, rest) = input.split_at(length);
+    *input = rest;
+    String::from_utf8(string.to_vec()).map_err(|_| invalid("string is not UTF-8"))
+}
+
+fn read_location(input: &mut &[u8]) -> io::Result<Option<Location>> {
+    if read_u8(input)? == 0 {
+        return Ok(None);
+    }
+    let file: StrCow = read_string(input)?.into();
+    let line = read_u32(input)?;
+    let module_path: StrCow = read_string(input)?.into();
+    Ok(Some(Location { file, line, module_path }))
+}
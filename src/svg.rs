@@ -0,0 +1,220 @@
+use std::io::Write;
+use std::io::Result as IoResult;
+use super::{Span, Thread};
+use export::{Metric, lane_roots};
+use timeline::escape;
+
+/// The colors of the frames of a flamegraph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Palette {
+    /// Reds and yellows, like `flamegraph.pl`
+    Hot,
+    /// Greens, for memory profiles
+    Mem,
+    /// Blues, for I/O profiles
+    Io,
+    /// Shades of gray
+    Gray,
+}
+
+/// Which way the frames of an SVG flamegraph grow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    /// Top level spans at the bottom
+    Flamegraph,
+    /// Top level spans at the top
+    Icicle,
+}
+
+/// Controls what `dump_svg` draws.
+#[derive(Debug, Clone)]
+pub struct SvgOptions {
+    width: u32,
+    frame_height: u32,
+    palette: Palette,
+    title: Option<String>,
+    orientation: Orientation,
+    metric: Metric,
+}
+
+impl Default for SvgOptions {
+    fn default() -> SvgOptions {
+        SvgOptions {
+            width: 1200,
+            frame_height: 16,
+            palette: Palette::Hot,
+            title: None,
+            orientation: Orientation::Flamegraph,
+            metric: Metric::Time,
+        }
+    }
+}
+
+impl SvgOptions {
+    pub fn new() -> SvgOptions {
+        SvgOptions::default()
+    }
+
+    /// The width of the image in pixels.
+    pub fn width(mut self, width: u32) -> SvgOptions {
+        self.width = width;
+        self
+    }
+
+    /// The height of one frame in pixels.
+    pub fn frame_height(mut self, frame_height: u32) -> SvgOptions {
+        self.frame_height = frame_height;
+        self
+    }
+
+    pub fn palette(mut self, palette: Palette) -> SvgOptions {
+        self.palette = palette;
+        self
+    }
+
+    /// A title to show above the frames.
+    pub fn title<S: Into<String>>(mut self, title: S) -> SvgOptions {
+        self.title = Some(title.into());
+        self
+    }
+
+    pub fn orientation(mut self, orientation: Orientation) -> SvgOptions {
+        self.orientation = orientation;
+        self
+    }
+
+    /// What the frames are sized by, the time by default.  Use
+    /// `Metric::Bytes` with `Palette::Mem` for a memory flamegraph.
+    pub fn metric(mut self, metric: Metric) -> SvgOptions {
+        self.metric = metric;
+        self
+    }
+}
+
+const TITLE_HEIGHT: u32 = 28;
+const PADDING: f64 = 10.0;
+
+struct Layout<'a> {
+    options: &'a SvgOptions,
+    /// Pixels per nanosecond
+    scale: f64,
+    total: u64,
+    top: f64,
+    rows: u32,
+}
+
+impl<'a> Layout<'a> {
+    fn y(&self, depth: u32) -> f64 {
+        let row = match self.options.orientation {
+            Orientation::Flamegraph => self.rows - 1 - depth,
+            Orientation::Icicle => depth,
+        };
+        self.top + f64::from(row * self.options.frame_height)
+    }
+
+    fn frame<W: Write>(&self, out: &mut W, span: &Span, x: f64, depth: u32) -> IoResult<()> {
+        let value = self.options.metric.total(span);
+        let width = value as f64 * self.scale;
+        if width < 0.1 {
+            return Ok(());
+        }
+        let y = self.y(depth);
+        let height = f64::from(self.options.frame_height);
+        let percent = if self.total == 0 { 100.0 } else { 100.0 * value as f64 / self.total as f64 };
+        let mut details = format!("{:.3}ms, {:.2}%", span.weighted_delta() as f64 / 1_000_000.0, percent);
+        if let Some(alloc) = span.alloc {
+            details.push_str(&format!(", {} allocations, {} bytes", alloc.allocations, alloc.bytes_allocated));
+        }
+        writeln!(out, r#"<g><title>{} ({})</title>"#, escape(&span.name), details)?;
+        writeln!(out, r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}" fill="{}" rx="2" ry="2"/>"#,
+                 x, y, width, height - 1.0, self.color(&span.name))?;
+        // Roughly 7 pixels per character of a 12px font.
+        let fits = ((width - 6.0) / 7.0) as usize;
+        let chars = span.name.chars().count();
+        if fits >= 3 {
+            let label: String = if chars <= fits {
+                span.name.to_string()
+            } else {
+                span.name.chars().take(fits - 2).chain("..".chars()).collect()
+            };
+            writeln!(out, r#"<text x="{:.2}" y="{:.2}">{}</text>"#, x + 3.0, y + height - 4.0, escape(&label))?;
+        }
+        writeln!(out, "</g>")?;
+
+        let mut child_x = x;
+        for child in &span.children {
+            self.frame(out, child, child_x, depth + 1)?;
+            child_x += self.options.metric.total(child) as f64 * self.scale;
+        }
+        Ok(())
+    }
+
+    fn color(&self, name: &str) -> String {
+        let hash = name.bytes().fold(2166136261u32, |hash, byte| (hash ^ u32::from(byte)).wrapping_mul(16777619));
+        let v1 = f64::from(hash & 0xff) / 255.0;
+        let v2 = f64::from((hash >> 8) & 0xff) / 255.0;
+        let v3 = f64::from((hash >> 16) & 0xff) / 255.0;
+        let (r, g, b) = match self.options.palette {
+            Palette::Hot => (205.0 + 50.0 * v3, 230.0 * v1, 55.0 * v2),
+            Palette::Mem => (0.0, 190.0 + 50.0 * v2, 210.0 * v1),
+            Palette::Io => (80.0 + 60.0 * v1, 80.0 + 60.0 * v1, 190.0 + 55.0 * v2),
+            Palette::Gray => {
+                let gray = 150.0 + 80.0 * v1;
+                (gray, gray, gray)
+            }
+        };
+        format!("rgb({},{},{})", r as u8, g as u8, b as u8)
+    }
+}
+
+/// Writes a static SVG flamegraph of `spans` that needs no JavaScript.
+///
+/// Frames are as wide as their share of the total time (or of the
+/// chosen `Metric`) and show their name, duration and allocations when
+/// hovered.
+pub fn dump_svg<W: Write>(mut out: W, spans: &[Span], options: &SvgOptions) -> IoResult<()> {
+    fn depth(spans: &[Span]) -> u32 {
+        spans.iter().map(|span| 1 + depth(&span.children)).max().unwrap_or(0)
+    }
+
+    let total: u64 = spans.iter().map(|span| options.metric.total(span)).sum();
+    let rows = depth(spans).max(1);
+    let title_height = if options.title.is_some() { TITLE_HEIGHT } else { 0 };
+    let height = title_height + rows * options.frame_height + 2 * PADDING as u32;
+    let layout = Layout {
+        options,
+        scale: (f64::from(options.width) - 2.0 * PADDING) / total.max(1) as f64,
+        total,
+        top: f64::from(title_height) + PADDING,
+        rows,
+    };
+
+    writeln!(out, r#"<?xml version="1.0" standalone="no"?>"#)?;
+    writeln!(out, r#"<svg version="1.1" width="{w}" height="{h}" viewBox="0 0 {w} {h}" xmlns="http://www.w3.org/2000/svg">"#,
+             w = options.width, h = height)?;
+    writeln!(out, r#"<style>text {{ font-family: Verdana, sans-serif; font-size: 12px; fill: black; pointer-events: none; }} rect:hover {{ stroke: black; stroke-width: 0.5; }}</style>"#)?;
+    writeln!(out, r#"<rect x="0" y="0" width="{}" height="{}" fill="white"/>"#, options.width, height)?;
+    if let Some(ref title) = options.title {
+        writeln!(out, r#"<text x="{}" y="20" text-anchor="middle" style="font-size: 17px">{}</text>"#,
+                 options.width / 2, escape(title))?;
+    }
+
+    let mut x = PADDING;
+    for span in spans {
+        layout.frame(&mut out, span, x, 0)?;
+        x += options.metric.total(span) as f64 * layout.scale;
+    }
+    writeln!(out, "</svg>")
+}
+
+/// Writes a static SVG flamegraph of the spans of every thread in
+/// `threads`.  The async spans of a thread are drawn below frames named
+/// `async lane N`, one for each lane.
+pub fn dump_svg_threads<W: Write>(out: W, threads: &[Thread], options: &SvgOptions) -> IoResult<()> {
+    let mut spans = vec![];
+    for thread in threads {
+        spans.extend(thread.spans.iter().cloned());
+        spans.extend(lane_roots(thread));
+    }
+    dump_svg(out, &spans, options)
+}
@@ -0,0 +1,403 @@
+use std::io::{IsTerminal, Result as IoResult, Write};
+
+use super::{SnapshotOptions, Span, Thread};
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+
+/// Whether the text report uses ANSI colors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color when writing to a terminal and `NO_COLOR` isn't set
+    Auto,
+    Always,
+    Never,
+}
+
+/// The unit that durations are printed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeUnit {
+    /// Pick the largest unit that keeps the value at or above 1
+    Auto,
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+/// The order that sibling spans are shown in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    /// In the order they started
+    Start,
+    /// Longest first
+    Time,
+    /// Alphabetically
+    Name,
+}
+
+/// Controls what `dump_text_with` and `dump_stdout_with` print.
+#[derive(Debug, Clone)]
+pub struct TextReportOptions {
+    color: ColorChoice,
+    bar_width: usize,
+    percent_of_parent: bool,
+    percent_of_total: bool,
+    unit: TimeUnit,
+    precision: usize,
+    max_depth: Option<usize>,
+    min_percent: f64,
+    sort: SortOrder,
+    thread_names: bool,
+    include_open: bool,
+}
+
+impl Default for TextReportOptions {
+    fn default() -> TextReportOptions {
+        TextReportOptions {
+            color: ColorChoice::Auto,
+            bar_width: 20,
+            percent_of_parent: true,
+            percent_of_total: true,
+            unit: TimeUnit::Auto,
+            precision: 3,
+            max_depth: None,
+            min_percent: 0.0,
+            sort: SortOrder::Start,
+            thread_names: true,
+            include_open: false,
+        }
+    }
+}
+
+impl TextReportOptions {
+    pub fn new() -> TextReportOptions {
+        TextReportOptions::default()
+    }
+
+    /// Use ANSI colors.  `Auto` only colors stdout when it is a terminal.
+    pub fn color(mut self, color: ColorChoice) -> TextReportOptions {
+        self.color = color;
+        self
+    }
+
+    /// Draw a bar of `width` characters showing each span's share of
+    /// its thread, or no bar for 0.
+    pub fn bars(mut self, width: usize) -> TextReportOptions {
+        self.bar_width = width;
+        self
+    }
+
+    /// Show the share of the parent span.
+    pub fn percent_of_parent(mut self, show: bool) -> TextReportOptions {
+        self.percent_of_parent = show;
+        self
+    }
+
+    /// Show the share of everything the thread recorded.
+    pub fn percent_of_total(mut self, show: bool) -> TextReportOptions {
+        self.percent_of_total = show;
+        self
+    }
+
+    pub fn unit(mut self, unit: TimeUnit) -> TextReportOptions {
+        self.unit = unit;
+        self
+    }
+
+    /// The number of digits after the decimal point of durations.
+    pub fn precision(mut self, precision: usize) -> TextReportOptions {
+        self.precision = precision;
+        self
+    }
+
+    /// Don't print spans nested deeper than `depth`, with 0 being the
+    /// top level spans.  Their time is part of their parent's remainder.
+    pub fn max_depth(mut self, depth: usize) -> TextReportOptions {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Don't print spans that took less than `percent` of their thread.
+    pub fn min_percent(mut self, percent: f64) -> TextReportOptions {
+        self.min_percent = percent;
+        self
+    }
+
+    pub fn sort(mut self, sort: SortOrder) -> TextReportOptions {
+        self.sort = sort;
+        self
+    }
+
+    /// Show the thread names next to the ids in the headers.
+    pub fn thread_names(mut self, show: bool) -> TextReportOptions {
+        self.thread_names = show;
+        self
+    }
+
+    /// Also print the spans of the session that haven't ended yet, see
+    /// `SnapshotOptions::include_open`.
+    pub fn include_open(mut self, include_open: bool) -> TextReportOptions {
+        self.include_open = include_open;
+        self
+    }
+
+    pub(crate) fn snapshot(&self) -> SnapshotOptions {
+        SnapshotOptions::new().include_open(self.include_open)
+    }
+}
+
+struct Report<'a> {
+    options: &'a TextReportOptions,
+    color: bool,
+    total: u64,
+}
+
+impl<'a> Report<'a> {
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.color && !code.is_empty() {
+            format!("{}{}{}", code, text, RESET)
+        } else {
+            text.to_owned()
+        }
+    }
+
+    fn duration(&self, ns: u64) -> String {
+        let unit = match self.options.unit {
+            TimeUnit::Auto if ns < 1_000 => TimeUnit::Nanoseconds,
+            TimeUnit::Auto if ns < 1_000_000 => TimeUnit::Microseconds,
+            TimeUnit::Auto if ns < 1_000_000_000 => TimeUnit::Milliseconds,
+            TimeUnit::Auto => TimeUnit::Seconds,
+            unit => unit,
+        };
+        let (divisor, suffix) = match unit {
+            TimeUnit::Nanoseconds => return format!("{}ns", ns),
+            TimeUnit::Microseconds => (1_000.0, "µs"),
+            TimeUnit::Milliseconds => (1_000_000.0, "ms"),
+            _ => (1_000_000_000.0, "s"),
+        };
+        format!("{:.*}{}", self.options.precision, ns as f64 / divisor, suffix)
+    }
+
+    fn percent(part: u64, whole: u64) -> f64 {
+        if whole == 0 { 100.0 } else { 100.0 * part as f64 / whole as f64 }
+    }
+
+    fn sorted<'s>(&self, spans: &'s [Span]) -> Vec<&'s Span> {
+        let mut spans: Vec<&Span> = spans.iter()
+            .filter(|span| Report::percent(span.weighted_delta(), self.total) >= self.options.min_percent)
+            .collect();
+        match self.options.sort {
+            SortOrder::Start => {}
+            SortOrder::Time => spans.sort_by_key(|span| ::std::cmp::Reverse(span.weighted_delta())),
+            SortOrder::Name => spans.sort_by(|a, b| a.name.cmp(&b.name)),
+        }
+        spans
+    }
+
+    fn span<W: Write>(&self, out: &mut W, span: &Span, parent: u64, depth: usize) -> IoResult<()> {
+        let time = span.weighted_delta();
+        let share = Report::percent(time, self.total);
+        let mut line = "  ".repeat(depth);
+        line.push_str("| ");
+        line.push_str(&self.paint(BOLD, &span.name));
+        line.push_str(": ");
+        let time_color = if share >= 20.0 { RED } else if share >= 5.0 { YELLOW } else { "" };
+        line.push_str(&self.paint(time_color, &self.duration(time)));
+
+        if self.options.bar_width > 0 {
+            let filled = ((share / 100.0 * self.options.bar_width as f64).round() as usize).min(self.options.bar_width);
+            line.push_str("  ");
+            line.push_str(&self.paint(GREEN, &"█".repeat(filled)));
+            line.push_str(&self.paint(DIM, &"░".repeat(self.options.bar_width - filled)));
+        }
+        let mut percents = vec![];
+        if self.options.percent_of_parent && depth > 0 {
+            percents.push(format!("{:.1}% of parent", Report::percent(time, parent)));
+        }
+        if self.options.percent_of_total {
+            percents.push(format!("{:.1}% of total", share));
+        }
+        if !percents.is_empty() {
+            line.push_str("  ");
+            line.push_str(&percents.join(", "));
+        }
+
+        let mut notes = String::new();
+        if let Some(cpu_ns) = span.cpu_ns {
+            let share = Report::percent(cpu_ns, span.delta);
+            notes.push_str(&format!(" [cpu {}, {:.0}%]", self.duration(cpu_ns.saturating_mul(span.weight)), share));
+        }
+        if let Some(ref alloc) = span.alloc {
+            notes.push_str(&format!(" [alloc {} bytes in {} allocations, peak {} bytes]",
+                                    alloc.bytes_allocated.saturating_mul(span.weight),
+                                    alloc.allocations.saturating_mul(span.weight),
+                                    alloc.peak_bytes));
+        }
+        if span.weight > 1 {
+            notes.push_str(&format!(" (sampled 1/{})", span.weight));
+        }
+        if let Some(lane) = span.lane {
+            notes.push_str(&format!(" (async lane {})", lane));
+        }
+        if span.raw_delta != span.delta {
+            notes.push_str(&format!(" (raw {})", self.duration(span.raw_delta.saturating_mul(span.weight))));
+        }
+        if let Some(ref location) = span.location {
+            notes.push_str(&format!(" ({})", location));
+        }
+        if !span.attributes.is_empty() {
+            let attributes: Vec<String> = span.attributes.iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            notes.push_str(&format!(" {{{}}}", attributes.join(", ")));
+        }
+        line.push_str(&self.paint(DIM, &notes));
+        if span.is_open {
+            line.push_str(&self.paint(CYAN, " (open)"));
+        }
+        if span.panicked {
+            line.push_str(&self.paint(RED, " (panicked)"));
+        }
+        writeln!(out, "{}", line)?;
+
+        if span.children.is_empty() {
+            return Ok(());
+        }
+        let mut remainder = time;
+        if self.options.max_depth.map_or(true, |max| depth < max) {
+            for child in self.sorted(&span.children) {
+                remainder = remainder.saturating_sub(child.weighted_delta());
+                self.span(out, child, time, depth + 1)?;
+            }
+        }
+        writeln!(out, "{}+ {}", "  ".repeat(depth + 1), self.paint(DIM, &self.duration(remainder)))
+    }
+}
+
+/// Writes the plain report of `dump_text_to_writer` and `dump_stdout`.
+pub(crate) fn write_plain<W: Write>(mut out: W, threads: &[Thread]) -> IoResult<()> {
+    fn print_span<W: Write>(span: &Span, out: &mut W) -> IoResult<f32> {
+        let mut buf = String::new();
+        for _ in 0 .. span.depth {
+            buf.push_str("  ");
+        }
+        buf.push_str("| ");
+        let ms = span.weighted_delta() as f32 / 1000000.0;
+        buf.push_str(&format!("{}: {}ms", span.name, ms));
+        if let Some(cpu_ns) = span.cpu_ns {
+            let cpu_ms = cpu_ns.saturating_mul(span.weight) as f32 / 1000000.0;
+            let share = if span.delta == 0 { 100.0 } else { 100.0 * cpu_ns as f32 / span.delta as f32 };
+            buf.push_str(&format!(" [cpu {}ms, {:.0}%]", cpu_ms, share));
+        }
+        if let Some(ref alloc) = span.alloc {
+            buf.push_str(&format!(" [alloc {} bytes in {} allocations, peak {} bytes]",
+                                  alloc.bytes_allocated.saturating_mul(span.weight),
+                                  alloc.allocations.saturating_mul(span.weight),
+                                  alloc.peak_bytes));
+        }
+        if span.weight > 1 {
+            buf.push_str(&format!(" (sampled 1/{})", span.weight));
+        }
+        if let Some(lane) = span.lane {
+            buf.push_str(&format!(" (async lane {})", lane));
+        }
+        if span.raw_delta != span.delta {
+            let raw_ms = span.raw_delta.saturating_mul(span.weight) as f32 / 1000000.0;
+            buf.push_str(&format!(" (raw {}ms)", raw_ms));
+        }
+        if span.is_open {
+            buf.push_str(" (open)");
+        }
+        if span.panicked {
+            buf.push_str(" (panicked)");
+        }
+        if let Some(ref location) = span.location {
+            buf.push_str(&format!(" ({})", location));
+        }
+        writeln!(out, "{}", buf)?;
+        let mut missing = ms;
+        for child in &span.children {
+            missing -= print_span(child, out)?;
+        }
+
+        if !span.children.is_empty() {
+            let mut buf = String::new();
+            for _ in 0 ..= span.depth {
+                buf.push_str("  ");
+            }
+            buf.push_str("+ ");
+            buf.push_str(&format!("{}ms", missing));
+            writeln!(out, "{}", buf)?;
+        }
+
+        Ok(ms)
+    }
+
+    for thread in threads {
+        writeln!(out, "THREAD: {}", thread.id)?;
+        for span in thread.spans.iter().chain(&thread.async_spans) {
+            print_span(span, &mut out)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Writes the report for `threads` with the given options.  `Auto`
+/// colors are only used if `terminal` is set.
+pub(crate) fn write_report<W: Write>(mut out: W, threads: &[Thread], options: &TextReportOptions,
+                                     terminal: bool) -> IoResult<()> {
+    let color = match options.color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => terminal && ::std::env::var_os("NO_COLOR").is_none(),
+    };
+    for thread in threads {
+        let total: u64 = thread.spans.iter().map(Span::weighted_delta).sum();
+        let report = Report { options, color, total };
+        let mut header = match thread.name {
+            Some(ref name) if options.thread_names => format!("THREAD: {} ({})", name, thread.id),
+            _ => format!("THREAD: {}", thread.id),
+        };
+        if let Some(ref parent) = thread.parent {
+            header.push_str(&format!(" from {}", parent.thread_id));
+            if let Some(ref span) = parent.span {
+                header.push_str(&format!(" in {}", span));
+            }
+        }
+        writeln!(out, "{}  {}", report.paint(BOLD, &header), report.duration(total))?;
+        for span in report.sorted(&thread.spans) {
+            report.span(&mut out, span, total, 0)?;
+        }
+        for span in &thread.async_spans {
+            report.span(&mut out, span, total, 0)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Writes the text report of `threads` to `out`.
+pub fn dump_text_custom<W: Write>(out: W, threads: &[Thread], options: &TextReportOptions) -> IoResult<()> {
+    write_report(out, threads, options, false)
+}
+
+/// Writes the text report of the current session to `out`.
+pub fn dump_text_with<W: Write>(out: W, options: &TextReportOptions) -> IoResult<()> {
+    ::session::with_current(|session| session.dump_text_with(out, options))
+}
+
+/// Prints the text report of the current session to stdout.
+pub fn dump_stdout_with(options: &TextReportOptions) {
+    ::session::with_current(|session| session.dump_stdout_with(options))
+}
+
+pub(crate) fn stdout_is_terminal() -> bool {
+    ::std::io::stdout().is_terminal()
+}
@@ -0,0 +1,193 @@
+use std::io::Write;
+use std::io::Result as IoResult;
+use super::{Span, Thread};
+use marks::Mark;
+
+const WIDTH: f64 = 1200.0;
+const ROW: f64 = 18.0;
+const HEADER: f64 = 22.0;
+const AXIS: f64 = 24.0;
+
+/// Writes a page that shows the spans of every thread on a shared
+/// time axis, one lane per thread and one row per depth, followed by
+/// one row per async lane.  Frame marks are dashed lines in the lane,
+/// the marks of `Thread::marks` are lines across all threads.  The
+/// tooltip of a span shows its allocations when they were tracked.
+///
+/// Unlike the flamegraph from `dump_html`, this shows when things
+/// happened and how the threads overlapped.
+pub fn dump_timeline_custom<W: Write>(out: W, threads: &[Thread]) -> IoResult<()> {
+    dump_timeline_with_marks(out, threads, &[])
+}
+
+/// Like `dump_timeline_custom`, with a line across all threads for
+/// every mark in `marks` as well.
+pub fn dump_timeline_with_marks<W: Write>(mut out: W, threads: &[Thread], marks: &[Mark]) -> IoResult<()> {
+    let marks: Vec<&Mark> = threads.iter().flat_map(|thread| &thread.marks).chain(marks).collect();
+    let mut start = u64::MAX;
+    let mut end = 0;
+    for thread in threads {
+        for span in thread.spans.iter().chain(&thread.async_spans) {
+            start = start.min(span.start_ns);
+            end = end.max(span.end_ns);
+        }
+        for &instant in thread.notes.iter().map(|note| &note.instant).chain(&thread.frame_marks) {
+            start = start.min(instant);
+            end = end.max(instant);
+        }
+    }
+    for mark in &marks {
+        start = start.min(mark.instant);
+        end = end.max(mark.instant);
+    }
+    if start > end {
+        start = 0;
+        end = 0;
+    }
+    let scale = WIDTH / (end - start).max(1) as f64;
+
+    let height = AXIS + threads.iter().map(|thread| HEADER + ROW * rows(thread) as f64).sum::<f64>();
+
+    write!(out, r#"<!doctype html>
+<html>
+    <head>
+        <meta charset="utf-8">
+        <title>flame timeline</title>
+        <style>
+            body {{ margin: 0; font-family: sans-serif; }}
+            svg {{ width: 100%; }}
+            rect.span {{ stroke: white; stroke-width: 0.5; }}
+            text {{ font-size: 11px; pointer-events: none; }}
+            text.thread {{ font-weight: bold; }}
+            line.mark {{ stroke: crimson; stroke-width: 1; }}
+            line.frame {{ stroke: gray; stroke-width: 1; stroke-dasharray: 2 2; }}
+        </style>
+    </head>
+    <body>
+        <svg viewBox="0 0 {} {}" xmlns="http://www.w3.org/2000/svg">
+"#, WIDTH, height)?;
+
+    for tick in 0 .. 10 {
+        let x = WIDTH * f64::from(tick) / 10.0;
+        let ms = (end - start) as f64 * f64::from(tick) / 10.0 / 1_000_000.0;
+        writeln!(out, r#"<line x1="{x}" x2="{x}" y1="0" y2="{}" stroke="lightgray"/>"#, height, x = x)?;
+        writeln!(out, r#"<text x="{}" y="14">{:.3}ms</text>"#, x + 2.0, ms)?;
+    }
+
+    let mut y = AXIS;
+    for thread in threads {
+        let name = match thread.name {
+            Some(ref name) => format!("{} ({})", name, thread.id),
+            None => format!("thread {}", thread.id),
+        };
+        writeln!(out, r#"<text class="thread" x="2" y="{}">{}</text>"#, y + 15.0, escape(&name))?;
+        for note in &thread.notes {
+            let label = match note.description {
+                Some(ref description) => format!("{}: {}", note.name, description),
+                None => note.name.to_string(),
+            };
+            writeln!(out, r#"<g><title>{}</title><circle cx="{}" cy="{}" r="4" fill="steelblue"/></g>"#,
+                     escape(&label), note.instant.saturating_sub(start) as f64 * scale, y + HEADER / 2.0)?;
+        }
+        y += HEADER;
+        for span in &thread.spans {
+            dump_span(&mut out, span, start, scale, y, 0)?;
+        }
+        let async_top = y + ROW * lane_depth(&thread.spans) as f64;
+        for span in &thread.async_spans {
+            dump_span(&mut out, span, start, scale, async_top, span.lane.unwrap_or(0))?;
+        }
+        for &ns in &thread.frame_marks {
+            let x = ns.saturating_sub(start) as f64 * scale;
+            writeln!(out, r#"<line class="frame" x1="{x}" x2="{x}" y1="{}" y2="{}"/>"#,
+                     y - HEADER, y + ROW * rows(thread) as f64, x = x)?;
+        }
+        y += ROW * rows(thread) as f64;
+    }
+
+    for mark in &marks {
+        let x = mark.instant.saturating_sub(start) as f64 * scale;
+        writeln!(out, r#"<g><title>{}</title><line class="mark" x1="{x}" x2="{x}" y1="{}" y2="{}"/>"#,
+                 escape(&mark.name), AXIS, height, x = x)?;
+        writeln!(out, r#"<text x="{}" y="{}">{}</text></g>"#, x + 2.0, AXIS - 2.0, escape(&mark.name))?;
+    }
+
+    write!(out, r#"        </svg>
+    </body>
+</html>"#)
+}
+
+fn dump_span<W: Write>(out: &mut W, span: &Span, start: u64, scale: f64, top: f64, depth: u32) -> IoResult<()> {
+    let x = (span.start_ns.saturating_sub(start)) as f64 * scale;
+    let width = (span.delta as f64 * scale).max(0.5);
+    let y = top + ROW * f64::from(depth);
+    let fill = if span.panicked {
+        "crimson".to_owned()
+    } else if span.is_open {
+        "lightsteelblue".to_owned()
+    } else {
+        color(&span.name)
+    };
+    let name = escape(&span.name);
+    let mut details = format!("{}ms", span.delta as f64 / 1_000_000.0);
+    if let Some(alloc) = span.alloc {
+        details.push_str(&format!(", {} allocations, {} bytes, peak {} bytes",
+                                  alloc.allocations, alloc.bytes_allocated, alloc.peak_bytes));
+    }
+    writeln!(out, r#"<g><title>{} ({})</title><rect class="span" x="{}" y="{}" width="{}" height="{}" fill="{}"/>"#,
+             name, details, x, y, width, ROW, fill)?;
+    // Roughly 6 pixels per character.
+    let fits = (width / 6.0) as usize;
+    if fits >= 3 {
+        let label: String = span.name.chars().take(fits).collect();
+        writeln!(out, r#"<text x="{}" y="{}">{}</text>"#, x + 2.0, y + 13.0, escape(&label))?;
+    }
+    writeln!(out, "</g>")?;
+
+    for child in &span.children {
+        dump_span(out, child, start, scale, top, depth + 1)?;
+    }
+    Ok(())
+}
+
+/// The number of rows of the lane of `thread`.
+fn rows(thread: &Thread) -> usize {
+    let async_lanes = thread.async_spans.iter().filter_map(|span| span.lane).max().map_or(0, |lane| lane as usize + 1);
+    lane_depth(&thread.spans) + async_lanes
+}
+
+/// The number of rows that the deepest span in `spans` needs.
+fn lane_depth(spans: &[Span]) -> usize {
+    fn depth(spans: &[Span]) -> usize {
+        spans.iter().map(|span| 1 + depth(&span.children)).max().unwrap_or(0)
+    }
+    depth(spans).max(1)
+}
+
+/// Picks a warm color from the name so that the same span always
+/// has the same color.
+fn color(name: &str) -> String {
+    let hash = name.bytes().fold(0u32, |hash, byte| hash.wrapping_mul(31).wrapping_add(u32::from(byte)));
+    let green = 80 + hash % 150;
+    let blue = (hash >> 8) % 60;
+    format!("rgb(230,{},{})", green, blue)
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn dump_timeline<W: Write>(out: W) -> IoResult<()> {
+    ::session::with_current(|session| session.dump_timeline(out))
+}
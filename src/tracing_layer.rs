@@ -0,0 +1,213 @@
+//! Records the spans and events of `tracing` into flame.
+
+use std::cell::RefCell;
+use std::fmt;
+
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Event, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use super::{Location, StrCow};
+use super::{end_event_by_id, set_attributes};
+use session::Session;
+
+/// A `tracing-subscriber` layer that records `tracing` spans as flame
+/// spans and `tracing` events as notes.
+///
+/// Every time a span is entered a flame span with the same name is
+/// started on the calling thread, and it ends when the span is exited
+/// again, so a span that is entered on several threads shows up on all
+/// of them.  Exiting a span only ends its own flame span, even if spans
+/// that were started inside of it with `flame::start` are still running.
+/// Entering a span that is already entered on the thread doesn't start
+/// another one.  The fields of spans and events are kept
+/// as `attributes`, and the `message` of an event is the name of its
+/// note.
+///
+/// Spans are recorded into the current session of the thread, see
+/// `with_session`.  Events outside of any span become notes of the
+/// thread.
+///
+/// ```no_run
+/// # extern crate flame;
+/// # extern crate tracing_subscriber;
+/// use tracing_subscriber::prelude::*;
+///
+/// # fn main() {
+/// tracing_subscriber::registry().with(flame::FlameLayer::new()).init();
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct FlameLayer {
+    _priv: (),
+}
+
+impl FlameLayer {
+    pub fn new() -> FlameLayer {
+        FlameLayer::default()
+    }
+}
+
+/// The fields of a span, kept in its extensions.
+struct Fields(Vec<(String, String)>);
+
+#[derive(Default)]
+struct Visitor {
+    message: Option<String>,
+    fields: Vec<(String, String)>,
+}
+
+impl Visit for Visitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format!("{:?}", value));
+    }
+}
+
+impl Visitor {
+    fn record(&mut self, field: &Field, value: String) {
+        if field.name() == "message" {
+            self.message = Some(value);
+        } else {
+            self.fields.push((field.name().to_owned(), value));
+        }
+    }
+}
+
+/// A span that was entered on this thread.
+struct Entered {
+    id: Id,
+    /// What was started for it, or `None` if the span was already entered
+    started: Option<Started>,
+}
+
+enum Started {
+    /// The flame span `id`, which started at `start_ns`
+    Span { session: Session, id: u32, start_ns: u64 },
+    /// A flame span that sampling skipped
+    Skipped(Session),
+}
+
+thread_local! {
+    static ENTERED: RefCell<Vec<Entered>> = const { RefCell::new(vec![]) };
+}
+
+fn location(metadata: &Metadata<'static>) -> Option<Location> {
+    match (metadata.file(), metadata.line()) {
+        (Some(file), Some(line)) => Some(Location {
+            file: file.into(),
+            line,
+            module_path: metadata.module_path().unwrap_or("").into(),
+        }),
+        _ => None,
+    }
+}
+
+fn fields<S>(id: &Id, ctx: &Context<S>) -> Vec<(String, String)>
+where S: Subscriber + for<'a> LookupSpan<'a> {
+    ctx.span(id)
+        .and_then(|span| span.extensions().get::<Fields>().map(|fields| fields.0.clone()))
+        .unwrap_or_default()
+}
+
+impl<S> Layer<S> for FlameLayer where S: Subscriber + for<'a> LookupSpan<'a> {
+    fn on_new_span(&self, attributes: &Attributes, id: &Id, ctx: Context<S>) {
+        let mut visitor = Visitor::default();
+        attributes.record(&mut visitor);
+        if let Some(message) = visitor.message {
+            visitor.fields.push(("message".to_owned(), message));
+        }
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Fields(visitor.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<S>) {
+        let mut visitor = Visitor::default();
+        values.record(&mut visitor);
+        if let Some(message) = visitor.message {
+            visitor.fields.push(("message".to_owned(), message));
+        }
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            match extensions.get_mut::<Fields>() {
+                Some(fields) => {
+                    for (name, value) in visitor.fields {
+                        match fields.0.iter_mut().find(|field| field.0 == name) {
+                            Some(field) => field.1 = value,
+                            None => fields.0.push((name, value)),
+                        }
+                    }
+                }
+                None => extensions.insert(Fields(visitor.fields)),
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event, _ctx: Context<S>) {
+        let mut visitor = Visitor::default();
+        event.record(&mut visitor);
+        let metadata = event.metadata();
+        let name: StrCow = match visitor.message {
+            Some(message) => message.into(),
+            None => metadata.name().into(),
+        };
+        let fields = visitor.fields;
+        ::session::with_current(|session| session.note_with(name, None, location(metadata), fields));
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<S>) {
+        let metadata = match ctx.metadata(id) {
+            Some(metadata) => metadata,
+            None => return,
+        };
+        let fields = fields(id, &ctx);
+        let entered = ENTERED.with(|entered| entered.borrow().iter().any(|entered| entered.id == *id));
+        let started = if entered {
+            None
+        } else {
+            let session = Session::current();
+            Some(match session.start_at(metadata.name().into(), location(metadata)) {
+                Some(id) => {
+                    let start_ns = session.with_library(|library| {
+                        let event = library.current.event_mut(id);
+                        event.attributes = fields;
+                        event.start_ns
+                    });
+                    Started::Span { session, id, start_ns }
+                }
+                None => Started::Skipped(session),
+            })
+        };
+        ENTERED.with(|entered| entered.borrow_mut().push(Entered { id: id.clone(), started }));
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<S>) {
+        // Spans don't have to be exited in the order they were entered.
+        let exited = ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            let index = entered.iter().rposition(|entered| entered.id == *id)?;
+            entered.remove(index).started
+        });
+        // The span ends in the session it was started in, whichever
+        // session is current now.
+        match exited {
+            Some(Started::Span { session, id: event, start_ns }) => {
+                let fields = fields(id, &ctx);
+                session.with_library(|library| {
+                    set_attributes(library, event, start_ns, fields);
+                    end_event_by_id(library, event, start_ns);
+                });
+            }
+            Some(Started::Skipped(session)) => {
+                session.end_unsampled();
+            }
+            None => {}
+        }
+    }
+}
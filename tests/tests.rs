@@ -1,134 +1,1450 @@
 extern crate flame;
+#[cfg(feature = "log")]
+extern crate log;
+extern crate serde_json;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "tracing")]
+extern crate tracing_subscriber;
+
+use std::alloc::System;
+
+#[global_allocator]
+static ALLOC: flame::alloc::FlameAlloc<System> = flame::alloc::FlameAlloc::new(System);
 
 #[test]
+#[allow(unused_must_use)]
 fn implicit_guarded_event() {
-    flame::clear();
-    flame::start_guard("foo");
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        flame::start_guard("foo");
+    });
 }
 
 #[test]
 fn named_guarded_event() {
-    flame::clear();
-    let _name = flame::start_guard("foo");
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        let _name = flame::start_guard("foo");
+    });
 }
 
 #[test]
 fn dropped_guarded_event() {
-    flame::clear();
-    let name = flame::start_guard("foo");
-    name.end();
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        let name = flame::start_guard("foo");
+        name.end();
+    });
 }
 
 #[test]
 #[allow(unreachable_code)]
 fn multiple_guard_early_return() {
-    flame::clear();
-    let _first = flame::start_guard("foo");
-    let _second = flame::start_guard("bar");
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        let _first = flame::start_guard("foo");
+        let _second = flame::start_guard("bar");
+    });
 }
 
 #[test]
 fn single_event() {
-    flame::clear();
-    flame::start("event1");
-    flame::end("event1");
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        flame::start("event1");
+        flame::end("event1");
 
-    let spans = flame::spans();
-    assert!(spans.len() == 1);
-    assert!(spans[0].name == "event1");
+        let spans = flame::spans();
+        assert!(spans.len() == 1);
+        assert!(spans[0].name == "event1");
+    });
 }
 
 #[test]
 fn single_nested() {
-    flame::clear();
-    flame::start("event1");
-        flame::start("event2");
-        flame::end("event2");
-    flame::end("event1");
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        flame::start("event1");
+            flame::start("event2");
+            flame::end("event2");
+        flame::end("event1");
 
-    let spans = flame::spans();
-    assert!(spans.len() == 1);
-    assert!(spans[0].name == "event1");
-    assert!(spans[0].depth == 0);
+        let spans = flame::spans();
+        assert!(spans.len() == 1);
+        assert!(spans[0].name == "event1");
+        assert!(spans[0].depth == 0);
 
-    let first = &spans[0];
-    assert!(first.children.len() == 1);
-    assert!(first.children[0].name == "event2");
-    assert!(first.children[0].depth == 1);
+        let first = &spans[0];
+        assert!(first.children.len() == 1);
+        assert!(first.children[0].name == "event2");
+        assert!(first.children[0].depth == 1);
+    });
 }
 
 #[test]
 fn double_nested() {
-    flame::clear();
-    flame::start("event1");
-        flame::start("event2");
-        flame::end("event2");
-        flame::start("event3");
-        flame::end("event3");
-    flame::end("event1");
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        flame::start("event1");
+            flame::start("event2");
+            flame::end("event2");
+            flame::start("event3");
+            flame::end("event3");
+        flame::end("event1");
 
-    let spans = flame::spans();
-    assert!(spans.len() == 1);
-    assert!(spans[0].name == "event1");
-    assert!(spans[0].depth == 0);
+        let spans = flame::spans();
+        assert!(spans.len() == 1);
+        assert!(spans[0].name == "event1");
+        assert!(spans[0].depth == 0);
 
-    let first = &spans[0];
-    assert!(first.children.len() == 2);
-    assert!(first.children[0].name == "event2");
-    assert!(first.children[1].name == "event3");
-    assert!(first.children[0].depth == 1);
-    assert!(first.children[1].depth == 1);
+        let first = &spans[0];
+        assert!(first.children.len() == 2);
+        assert!(first.children[0].name == "event2");
+        assert!(first.children[1].name == "event3");
+        assert!(first.children[0].depth == 1);
+        assert!(first.children[1].depth == 1);
+    });
 }
 
 #[test]
 fn threads() {
     use std::thread::spawn;
-    flame::clear();
-    flame::start("main thread");
-    let mut handles = vec![];
-
-    for i in 0 .. 10 {
-        handles.push(spawn(move || {
-            if i % 2 == 0 {
-                let s = format!("thread {}", i);
-                flame::start(s.clone());
-                flame::end(s);
-                flame::commit_thread();
-            }
-        }));
-    }
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        flame::start("main thread");
+        let mut handles = vec![];
 
-    for handle in handles {
-        handle.join().unwrap();
-    }
+        for i in 0 .. 10 {
+            let session = session.clone();
+            handles.push(spawn(move || flame::with_session(&session, || {
+                if i % 2 == 0 {
+                    let s = format!("thread {}", i);
+                    flame::start(s.clone());
+                    flame::end(s);
+                    flame::commit_thread();
+                }
+            })));
+        }
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
 
-    flame::end("main thread");
+        flame::end("main thread");
 
-    let threads = flame::threads();
-    assert_eq!(threads.len(), 6);
+        let threads = flame::threads();
+        assert_eq!(threads.len(), 6);
+    });
 }
 
 #[test]
 #[should_panic]
 fn wrong_name() {
-    flame::clear();
-    flame::start("a");
-    flame::end("b");
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        flame::start("a");
+        flame::end("b");
+    });
 }
 
 #[test]
-#[should_panic]
-fn cant_note() {
-    flame::clear();
-    flame::note("hi", None);
+fn note_outside_of_span() {
+    let session = flame::Session::new();
+    session.note("hi", None);
+    session.start("span");
+    session.note("in span", None);
+    session.end("span");
+
+    let threads = session.threads();
+    assert_eq!(threads[0].notes.len(), 1);
+    assert_eq!(threads[0].notes[0].name, "hi");
+    assert_eq!(threads[0].spans[0].notes[0].name, "in span");
 }
 
 #[test]
 fn end_with() {
     fn _inner() -> u32 {
-        flame::clear();
         flame::start("w");
         flame::end_with("w", 1)
     }
-    assert_eq!(1, _inner());
+    let session = flame::Session::new();
+    assert_eq!(1, flame::with_session(&session, _inner));
+}
+
+#[test]
+fn locations() {
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        flame::span!("outer");
+        {
+            let _inner = flame::guard!("inner");
+            flame::note!("note", "description");
+        }
+        flame::end("outer");
+        flame::start("plain");
+        flame::end("plain");
+
+        let spans = flame::spans();
+        let outer = spans[0].location.as_ref().unwrap();
+        assert_eq!(outer.file, file!());
+        assert_eq!(outer.module_path, module_path!());
+
+        let inner = &spans[0].children[0];
+        let note = &inner.notes[0];
+        assert_eq!(inner.location.as_ref().unwrap().line + 1, note.location.as_ref().unwrap().line);
+        assert_eq!(note.description.as_ref().unwrap(), "description");
+        assert!(spans[1].location.is_none());
+    });
+}
+
+#[test]
+fn live_threads() {
+    use std::sync::mpsc::channel;
+    use std::thread::Builder;
+
+    let session = flame::Session::new();
+    let worker_session = session.clone();
+    let (recorded_tx, recorded_rx) = channel();
+    let (done_tx, done_rx) = channel::<()>();
+    let handle = Builder::new().name("live worker".into()).spawn(move || {
+        worker_session.start("outer");
+        worker_session.start("inner");
+        worker_session.end("inner");
+        recorded_tx.send(()).unwrap();
+        done_rx.recv().unwrap();
+        worker_session.end("outer");
+    }).unwrap();
+
+    recorded_rx.recv().unwrap();
+    let threads = session.threads_with(&flame::SnapshotOptions::new().include_open(true));
+    let worker = threads.iter().find(|t| t.name.as_ref().map(|n| &n[..]) == Some("live worker")).unwrap();
+    assert_eq!(worker.spans.len(), 1);
+    assert_eq!(worker.spans[0].name, "outer");
+    assert!(worker.spans[0].is_open);
+    assert_eq!(worker.spans[0].children[0].name, "inner");
+
+    done_tx.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn clear_covers_live_threads() {
+    use std::sync::mpsc::channel;
+    use std::thread::spawn;
+
+    let session = flame::Session::new();
+    let worker_session = session.clone();
+    let (recorded_tx, recorded_rx) = channel();
+    let (cleared_tx, cleared_rx) = channel::<()>();
+    let handle = spawn(move || {
+        worker_session.start("running");
+        worker_session.start("finished");
+        worker_session.end("finished");
+        worker_session.note("dropped", None);
+        recorded_tx.send(()).unwrap();
+        cleared_rx.recv().unwrap();
+        worker_session.start("after clear");
+        worker_session.end("after clear");
+        worker_session.end("running");
+        worker_session.commit_thread();
+    });
+
+    recorded_rx.recv().unwrap();
+    session.start("on this thread");
+    session.clear();
+    let open = flame::SnapshotOptions::new().include_open(true);
+    let threads = session.threads_with(&open);
+    assert!(threads[0].spans.is_empty());
+    assert_eq!(threads[1].spans.len(), 1);
+    assert_eq!(threads[1].spans[0].name, "running");
+    assert!(threads[1].spans[0].children.is_empty());
+    assert!(threads[1].spans[0].notes.is_empty());
+
+    cleared_tx.send(()).unwrap();
+    handle.join().unwrap();
+    let threads = session.threads();
+    assert_eq!(threads[1].spans[0].name, "running");
+    assert_eq!(threads[1].spans[0].children[0].name, "after clear");
+}
+
+#[test]
+fn clear_after_mismatched_end() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::mpsc::channel;
+    use std::thread::spawn;
+
+    let session = flame::Session::new();
+    let worker_session = session.clone();
+    let (recorded_tx, recorded_rx) = channel();
+    let (cleared_tx, cleared_rx) = channel::<()>();
+    let handle = spawn(move || {
+        worker_session.start("running");
+        worker_session.start("a");
+        assert!(catch_unwind(AssertUnwindSafe(|| worker_session.end("b"))).is_err());
+        recorded_tx.send(()).unwrap();
+        cleared_rx.recv().unwrap();
+        worker_session.start("after clear");
+        worker_session.end("after clear");
+        worker_session.commit_thread();
+    });
+
+    recorded_rx.recv().unwrap();
+    session.clear();
+    cleared_tx.send(()).unwrap();
+    handle.join().unwrap();
+
+    // Whether "running" is still open depends on other tests having
+    // installed the panic hook, so only "a" and "after clear" are checked.
+    let threads = session.threads_with(&flame::SnapshotOptions::new().include_open(true));
+    let mut names = vec![];
+    let mut spans: Vec<&flame::Span> = threads.iter().flat_map(|thread| &thread.spans).collect();
+    while let Some(span) = spans.pop() {
+        names.push(&span.name[..]);
+        spans.extend(&span.children);
+    }
+    assert!(names.contains(&"after clear"));
+    assert!(!names.contains(&"a"));
+}
+
+#[test]
+fn open_spans() {
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        flame::start("open");
+            flame::start("finished");
+            flame::end("finished");
+            flame::start("also open");
+
+        assert!(flame::spans().is_empty());
+
+        let spans = flame::spans_with(&flame::SnapshotOptions::new().include_open(true));
+        assert_eq!(spans.len(), 1);
+        assert!(spans[0].is_open);
+        assert_eq!(spans[0].children.len(), 2);
+        assert!(!spans[0].children[0].is_open);
+        assert!(spans[0].children[1].is_open);
+        assert!(spans[0].end_ns >= spans[0].children[1].end_ns);
+
+            flame::end("also open");
+        flame::end("open");
+    });
+}
+
+#[test]
+fn dumps_leave_out_open_spans() {
+    let session = flame::Session::new();
+    session.start("dumped open");
+
+    let text = |options: &flame::TextReportOptions| {
+        let mut text = vec![];
+        session.dump_text_with(&mut text, options).unwrap();
+        String::from_utf8(text).unwrap()
+    };
+    let mut plain = vec![];
+    session.dump_text_to_writer(&mut plain).unwrap();
+    assert!(!String::from_utf8(plain).unwrap().contains("dumped open"));
+    assert!(!text(&flame::TextReportOptions::new()).contains("dumped open"));
+    assert!(text(&flame::TextReportOptions::new().include_open(true)).contains("dumped open"));
+
+    let html = |options: &flame::HtmlOptions| {
+        let mut html = vec![];
+        session.dump_html_with(&mut html, options).unwrap();
+        String::from_utf8(html).unwrap()
+    };
+    assert!(!html(&flame::HtmlOptions::new()).contains("dumped open"));
+    assert!(html(&flame::HtmlOptions::new().include_open(true)).contains("dumped open"));
+
+    #[cfg(feature = "json")]
+    {
+        let mut json = vec![];
+        session.dump_json(&mut json).unwrap();
+        assert!(flame::load_json(&json[..]).unwrap()[0].spans.is_empty());
+        let mut json = vec![];
+        session.dump_json_with(&mut json, &flame::SnapshotOptions::new().include_open(true)).unwrap();
+        assert_eq!(flame::load_json(&json[..]).unwrap()[0].spans[0].name, "dumped open");
+    }
+    session.end("dumped open");
+}
+
+#[test]
+fn panic_hook() {
+    use std::thread::Builder;
+
+    flame::install_panic_hook();
+    let session = flame::Session::new();
+    let worker_session = session.clone();
+    let result = Builder::new().name("panicking".into()).spawn(move || {
+        flame::with_session(&worker_session, || {
+            let _outer = flame::start_guard("outer");
+            let _inner = flame::start_guard("inner");
+            panic!("boom");
+        })
+    }).unwrap().join();
+    assert!(result.is_err());
+
+    let threads = session.threads();
+    let thread = threads.iter().find(|t| t.name.as_ref().map(|n| &n[..]) == Some("panicking")).unwrap();
+    let outer = &thread.spans[0];
+    assert!(outer.panicked);
+    let inner = &outer.children[0];
+    assert!(inner.panicked);
+    assert_eq!(inner.notes[0].description.as_ref().unwrap(), "boom");
+    assert_eq!(inner.notes[0].location.as_ref().unwrap().file, file!());
+}
+
+#[test]
+fn sessions_are_independent() {
+    let outer = flame::Session::new();
+    let first = flame::Session::new();
+    let second = flame::Session::new();
+
+    flame::with_session(&outer, || {
+        flame::start("outer");
+        first.start("first");
+        flame::with_session(&second, || {
+            let _guard = flame::start_guard("second");
+            flame::with_session(&first, || flame::span_of("nested", || ()));
+        });
+        first.end("first");
+        flame::end("outer");
+    });
+
+    let outer_spans = outer.spans();
+    assert_eq!(outer_spans.len(), 1);
+    assert_eq!(outer_spans[0].name, "outer");
+    assert_eq!(outer_spans[0].children.len(), 0);
+
+    let first_spans = first.spans();
+    assert_eq!(first_spans.len(), 1);
+    assert_eq!(first_spans[0].name, "first");
+    assert_eq!(first_spans[0].children[0].name, "nested");
+
+    let second_spans = second.spans();
+    assert_eq!(second_spans.len(), 1);
+    assert_eq!(second_spans[0].name, "second");
+}
+
+#[test]
+fn sessions_share_an_epoch_across_threads() {
+    let session = flame::Session::new();
+    session.start("main");
+    let worker = session.clone();
+    std::thread::spawn(move || {
+        worker.start("worker");
+        worker.end("worker");
+    }).join().unwrap();
+    session.end("main");
+
+    let threads = session.threads();
+    assert_eq!(threads.len(), 2);
+    let main = &threads[0].spans[0];
+    let worker = &threads[1].spans[0];
+    assert!(main.start_ns <= worker.start_ns && worker.end_ns <= main.end_ns);
+}
+
+#[test]
+fn frames() {
+    let session = flame::Session::new();
+    session.set_frame_history(3);
+    session.start("game");
+    for i in 0 .. 5 {
+        session.start("update");
+        session.end("update");
+        if i == 4 {
+            session.start("spike");
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            session.end("spike");
+        }
+        session.frame_mark();
+    }
+    session.end("game");
+
+    let frames = session.frames();
+    assert_eq!(frames.iter().map(|f| f.number).collect::<Vec<_>>(), vec![2, 3, 4]);
+    // "game" was still running, so what finished inside of it is top level.
+    assert_eq!(frames[0].spans.len(), 1);
+    assert_eq!(frames[0].spans[0].name, "update");
+    assert_eq!(frames[2].spans.iter().map(|s| &s.name[..]).collect::<Vec<_>>(), vec!["update", "spike"]);
+    // The spans stay with the recording as well.
+    assert_eq!(session.spans()[0].children.len(), 6);
+    let threads = session.threads();
+
+    session.start("update");
+    session.end("update");
+    session.frame_mark();
+    // Dumped once the last frame has ended, so that it stays fast.
+    assert_eq!(threads[0].frame_marks.len(), 5);
+    let mut timeline = vec![];
+    flame::dump_timeline_custom(&mut timeline, &threads).unwrap();
+    assert_eq!(String::from_utf8(timeline).unwrap().matches(r#"<line class="frame""#).count(), 5);
+    #[cfg(feature = "json")]
+    {
+        let mut chrome = vec![];
+        flame::dump_chrome(&mut chrome, &threads).unwrap();
+        assert_eq!(String::from_utf8(chrome).unwrap().matches(r#""cat":"frame""#).count(), 5);
+    }
+    let frames = session.frames();
+    let last = frames.last().unwrap();
+    assert_eq!(last.spans.len(), 2);
+    assert_eq!(last.spans[0].name, "game");
+    assert!(last.spans[0].children.is_empty());
+    assert_eq!(last.spans[1].name, "update");
+    assert_eq!(session.spans().len(), 2);
+
+    let slowest = flame::slowest_frames(&frames, 1);
+    assert_eq!(slowest[0].number, 4);
+    let histogram = flame::frame_histogram(&frames, 1_000_000);
+    assert_eq!(histogram.iter().map(|&(_, count)| count).sum::<usize>(), 3);
+    assert!(histogram.last().unwrap().0 >= 5_000_000);
+}
+
+#[test]
+fn sampling() {
+    let session = flame::Session::new();
+    session.sample("hot", 10);
+    session.start("outer");
+    for _ in 0 .. 100 {
+        session.start("hot");
+        session.start("inside hot");
+        session.note("dropped with the unsampled spans", None);
+        session.end("inside hot");
+        session.end("hot");
+    }
+    session.end("outer");
+
+    let outer = &session.spans()[0];
+    assert_eq!(outer.weight, 1);
+    assert_eq!(outer.children.len(), 10);
+    assert!(outer.children.iter().all(|hot| hot.weight == 10));
+    assert_eq!(outer.children[0].children[0].weight, 10);
+
+    session.sample("hot", 1);
+    session.clear();
+    session.start("hot");
+    session.end("hot");
+    assert_eq!(session.spans()[0].weight, 1);
+}
+
+#[test]
+fn skipped_spans_are_only_counted() {
+    let session = flame::Session::new();
+    session.sample("hot", 2);
+    for _ in 0 .. 2 {
+        session.start("hot");
+        session.start("inside");
+        session.note("in a skipped span", None);
+    }
+    // The second "hot" was skipped, along with everything inside of it.
+    assert_eq!(session.end("inside"), 0);
+    assert_eq!(session.end("hot"), 0);
+    session.end("inside");
+    session.end("hot");
+
+    let spans = session.spans();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].children[0].notes.len(), 1);
+
+    // Clearing forgets about the skipped span that is still running.
+    session.start("hot");
+    session.start("hot");
+    session.clear();
+    session.start("after clear");
+    session.end("after clear");
+    assert_eq!(session.spans()[0].name, "after clear");
+}
+
+#[test]
+fn adaptive_sampling() {
+    let session = flame::Session::new();
+    session.sample_adaptive(Some(100));
+    for _ in 0 .. 1000 {
+        session.start("hot");
+        session.end("hot");
+    }
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    for _ in 0 .. 1000 {
+        session.start("hot");
+        session.end("hot");
+    }
+
+    let spans = session.spans();
+    assert!(spans.len() < 1200);
+    let estimated: u64 = spans.iter().map(|span| span.weight).sum();
+    assert!((1900 ..= 2100).contains(&estimated), "estimated {} calls", estimated);
+}
+
+#[test]
+fn overhead_correction() {
+    let measured = flame::calibrate();
+    assert!(measured.span_ns > 0);
+    assert_eq!(flame::overhead(), measured);
+
+    let session = flame::Session::new();
+    session.start("parent");
+    for _ in 0 .. 10 {
+        session.start("child");
+        session.end("child");
+    }
+    session.note("note", None);
+    session.end("parent");
+
+    // Use a made up overhead that is far below the real one.
+    flame::set_overhead(flame::Overhead { span_ns: 1, note_ns: 2 });
+    flame::set_overhead_correction(true);
+    let corrected = session.spans();
+    flame::set_overhead_correction(false);
+    let raw = session.spans();
+
+    assert_eq!(corrected[0].raw_delta, raw[0].delta);
+    assert_eq!(corrected[0].delta, raw[0].delta - 10 - 2);
+    assert_eq!(corrected[0].children[0].delta, raw[0].children[0].delta);
+}
+
+#[test]
+fn clocks() {
+    use flame::clock::{self, Clock, InstantClock};
+
+    fn check<C: Clock>(clock: &C) {
+        let first = clock.now_ns();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let second = clock.now_ns();
+        assert!(second >= first + 5_000_000);
+        // Every clock counts from the same starting point.
+        let anchor = clock::anchor_ns();
+        assert!((clock.now_ns() as i64 - anchor as i64).abs() < 5_000_000);
+    }
+
+    check(&InstantClock);
+    #[cfg(target_os = "linux")]
+    check(&clock::MonotonicRawClock::new());
+    #[cfg(target_arch = "x86_64")]
+    {
+        if let Some(tsc) = clock::TscClock::calibrate() {
+            check(&tsc);
+        }
+    }
+
+    flame::set_clock(InstantClock);
+    let session = flame::Session::new();
+    session.start("span");
+    session.end("span");
+    assert_eq!(session.spans().len(), 1);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn cpu_time() {
+    let session = flame::Session::new();
+    flame::set_cpu_time(true);
+    session.start("sleep");
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    session.end("sleep");
+    session.start("spin");
+    let start = std::time::Instant::now();
+    while start.elapsed() < std::time::Duration::from_millis(20) {}
+    session.end("spin");
+    flame::set_cpu_time(false);
+    session.start("off");
+    session.end("off");
+
+    let spans = session.spans();
+    let sleep = spans[0].cpu_ns.unwrap();
+    let spin = spans[1].cpu_ns.unwrap();
+    assert!(sleep < spans[0].delta / 2);
+    // Other tests compete for the CPU, so the spin isn't compared to
+    // its wall time.
+    assert!(spin > sleep);
+    assert_eq!(spans[2].cpu_ns, None);
+
+    let mut text = vec![];
+    session.dump_text_to_writer(&mut text).unwrap();
+    assert!(String::from_utf8(text).unwrap().contains("[cpu "));
+}
+
+#[test]
+fn allocations() {
+    let session = flame::Session::new();
+    let kept = session.start_guard("outer");
+    let buffer: Vec<u8> = flame::with_session(&session, || flame::span_of("allocate", || {
+        let scratch = vec![0u8; 4096];
+        drop(scratch);
+        vec![0u8; 1024]
+    }));
+    drop(kept);
+    drop(buffer);
+
+    let spans = session.spans();
+    let outer = spans[0].alloc.unwrap();
+    let inner = spans[0].children[0].alloc.unwrap();
+    assert!(inner.allocations >= 2);
+    assert!(inner.deallocations >= 1);
+    assert!(inner.bytes_allocated >= 4096 + 1024);
+    assert!(inner.bytes_freed >= 4096);
+    assert!(inner.peak_bytes >= 4096);
+    assert!(outer.bytes_allocated >= inner.bytes_allocated);
+    assert!(outer.peak_bytes >= inner.peak_bytes);
+
+    let mut text = vec![];
+    session.dump_text_to_writer(&mut text).unwrap();
+    assert!(String::from_utf8(text).unwrap().contains("[alloc "));
+
+    let threads = session.threads();
+    let mut folded = vec![];
+    flame::dump_folded_with(&mut folded, &threads, flame::Metric::Bytes).unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    let bytes: u64 = folded.lines()
+        .find(|line| line.starts_with("outer;allocate "))
+        .map(|line| line.rsplit(' ').next().unwrap().parse().unwrap())
+        .unwrap();
+    assert_eq!(bytes, inner.bytes_allocated);
+
+    let mut svg = vec![];
+    let options = flame::SvgOptions::new().metric(flame::Metric::Allocations);
+    flame::dump_svg(&mut svg, &spans, &options).unwrap();
+    let svg = String::from_utf8(svg).unwrap();
+    assert!(svg.contains(&format!("{} allocations, {} bytes", inner.allocations, inner.bytes_allocated)));
+
+    let mut timeline = vec![];
+    flame::dump_timeline_custom(&mut timeline, &threads).unwrap();
+    let timeline = String::from_utf8(timeline).unwrap();
+    assert!(timeline.contains(&format!("{} allocations, {} bytes, peak {} bytes",
+                                       inner.allocations, inner.bytes_allocated, inner.peak_bytes)));
+
+    #[cfg(feature = "json")]
+    {
+        let mut speedscope = vec![];
+        flame::dump_speedscope(&mut speedscope, &threads).unwrap();
+        let speedscope = String::from_utf8(speedscope).unwrap();
+        assert_eq!(speedscope.matches("\"type\":\"sampled\"").count(), 2);
+        assert!(speedscope.contains("\"unit\":\"bytes\""));
+    }
+}
+
+#[test]
+fn streaming() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let receiver = std::thread::spawn(move || {
+        flame::receive(listener)
+            .filter(|event| match *event {
+                flame::LiveEvent::Start { ref name, .. } |
+                flame::LiveEvent::End { ref name, .. } |
+                flame::LiveEvent::Note { ref name, .. } |
+                flame::LiveEvent::Begin { ref name, .. } |
+                flame::LiveEvent::Finish { ref name, .. } => name.starts_with("streamed"),
+            })
+            .take(9)
+            .collect::<Vec<_>>()
+    });
+
+    flame::install_panic_hook();
+    flame::stream_to(&addr).unwrap();
+    let session = flame::Session::new();
+    session.start("streamed outer");
+    session.start("streamed inner");
+    session.note("streamed note", Some("details"));
+    session.end("streamed inner");
+    session.end("streamed outer");
+    let request = session.begin("streamed request");
+    session.finish(request);
+    let worker_session = session.clone();
+    let result = std::thread::spawn(move || {
+        worker_session.start("streamed panic");
+        panic!("boom");
+    }).join();
+    assert!(result.is_err());
+    flame::stop_streaming();
+
+    let events = receiver.join().unwrap();
+    assert_eq!(events.len(), 9);
+    let (thread, outer_start) = match events[0] {
+        flame::LiveEvent::Start { thread_id, timestamp_ns, ref name, .. } => {
+            assert_eq!(name, "streamed outer");
+            (thread_id, timestamp_ns)
+        }
+        ref other => panic!("unexpected {:?}", other),
+    };
+    match events[2] {
+        flame::LiveEvent::Note { thread_id, ref description, .. } => {
+            assert_eq!(thread_id, thread);
+            assert_eq!(description.as_ref().map(|d| &d[..]), Some("details"));
+        }
+        ref other => panic!("unexpected {:?}", other),
+    }
+    match events[4] {
+        flame::LiveEvent::End { timestamp_ns, ref name, .. } => {
+            assert_eq!(name, "streamed outer");
+            assert!(timestamp_ns >= outer_start);
+        }
+        ref other => panic!("unexpected {:?}", other),
+    }
+    match (&events[5], &events[6]) {
+        (&flame::LiveEvent::Begin { id: begun, ref name, .. }, &flame::LiveEvent::Finish { id: finished, .. }) => {
+            assert_eq!(name, "streamed request");
+            assert_eq!(begun, finished);
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    // The panic hook ends the spans that the panic interrupted.
+    match events[8] {
+        flame::LiveEvent::End { ref name, .. } => assert_eq!(name, "streamed panic"),
+        ref other => panic!("unexpected {:?}", other),
+    }
+}
+
+#[test]
+fn malformed_stream_frames() {
+    use std::io::ErrorKind;
+
+    fn frame(name_length: u32, name: &[u8]) -> Vec<u8> {
+        let mut body = vec![2];
+        body.extend_from_slice(&7u64.to_be_bytes());
+        body.extend_from_slice(&11u64.to_be_bytes());
+        body.extend_from_slice(&name_length.to_be_bytes());
+        body.extend_from_slice(name);
+        let mut frame = (body.len() as u32).to_be_bytes().to_vec();
+        frame.extend(body);
+        frame
+    }
+
+    let event = flame::LiveEvent::read_from(&mut &frame(4, b"span")[..]).unwrap();
+    assert_eq!(event, flame::LiveEvent::End { thread_id: 7, timestamp_ns: 11, name: "span".into() });
+
+    // A string can't be longer than the frame it's in.
+    let error = flame::LiveEvent::read_from(&mut &frame(u32::MAX, b"span")[..]).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    let error = flame::LiveEvent::read_from(&mut &frame(2, &[0xff, 0xfe])[..]).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn serving() {
+    use std::io::{Read, Write};
+
+    fn get(addr: std::net::SocketAddr, target: &str) -> String {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    let session = flame::Session::new();
+    let addr = flame::with_session(&session, || flame::serve("127.0.0.1:0")).unwrap();
+    let worker = session.clone();
+    std::thread::Builder::new().name("served worker".into()).spawn(move || {
+        worker.start("served <span>");
+        worker.start("served short");
+        worker.end("served short");
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        worker.end("served <span>");
+        worker.commit_thread();
+    }).unwrap().join().unwrap();
+
+    let timeline = get(addr, "/timeline?thread=served+worker");
+    assert!(timeline.starts_with("HTTP/1.1 200 OK"));
+    assert!(timeline.contains("served &lt;span&gt;"));
+    assert!(timeline.contains("served short"));
+
+    let filtered = get(addr, "/timeline?min=1000000");
+    assert!(filtered.contains("served &lt;span&gt;"));
+    assert!(!filtered.contains("served short"));
+
+    let other_thread = get(addr, "/timeline?thread=nobody");
+    assert!(!other_thread.contains("served"));
+
+    assert!(get(addr, "/flamegraph?from=0").starts_with("HTTP/1.1 200 OK"));
+    #[cfg(feature = "json")]
+    assert!(get(addr, "/json").contains("\"served short\""));
+    assert!(get(addr, "/nothing").starts_with("HTTP/1.1 404"));
+    assert!(get(addr, "/timeline?min=soon").starts_with("HTTP/1.1 400"));
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn exporters() {
+    let session = flame::Session::new();
+    session.start("outer");
+    session.start("inner");
+    session.note("checkpoint", None);
+    session.end("inner");
+    session.end("outer");
+
+    let mut json = vec![];
+    session.dump_json(&mut json).unwrap();
+    let threads = flame::load_json(&json[..]).unwrap();
+    assert_eq!(threads[0].spans[0].name, "outer");
+    assert_eq!(threads[0].spans[0].children[0].notes[0].name, "checkpoint");
+
+    let mut folded = vec![];
+    flame::dump_folded(&mut folded, &threads).unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    assert!(folded.lines().any(|line| line.starts_with("outer;inner ")));
+
+    let mut chrome = vec![];
+    flame::dump_chrome(&mut chrome, &threads).unwrap();
+    let chrome = String::from_utf8(chrome).unwrap();
+    assert!(chrome.contains("\"ph\":\"X\"") && chrome.contains("\"checkpoint\""));
+
+    let mut speedscope = vec![];
+    flame::dump_speedscope(&mut speedscope, &threads).unwrap();
+    let speedscope = String::from_utf8(speedscope).unwrap();
+    assert_eq!(speedscope.matches("\"type\":\"O\"").count(), 2);
+    assert_eq!(speedscope.matches("\"type\":\"C\"").count(), 2);
+}
+
+#[test]
+#[cfg(feature = "json")]
+fn command_line_tool() {
+    use std::process::Command;
+
+    let session = flame::Session::new();
+    session.start("cli outer");
+    session.start("cli inner");
+    session.end("cli inner");
+    session.end("cli outer");
+    let request = session.begin("cli request");
+    session.finish(request);
+    session.frame_mark();
+    flame::mark("cli mark");
+    let path = std::env::temp_dir().join(format!("flame-cli-{}.json", std::process::id()));
+    session.dump_json(&mut std::fs::File::create(&path).unwrap()).unwrap();
+
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_flame")).args(args).output().unwrap();
+        assert!(output.status.success(), "{:?} failed", args);
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let path = path.to_str().unwrap();
+    assert!(run(&["top", path]).contains("cli inner"));
+    assert!(run(&["summary", path]).contains("1 threads"));
+    assert!(run(&["diff", path, path]).contains("cli outer"));
+    assert!(run(&["convert", path, "--format", "folded"]).contains("cli outer;cli inner"));
+    assert!(run(&["convert", path, "--format", "html"]).contains("cli inner"));
+    assert!(run(&["convert", path, "--format", "svg"]).contains("cli request"));
+    let merged = flame::load_json(run(&["merge", path, path]).as_bytes()).unwrap();
+    assert_eq!(merged.len(), 1);
+    assert_eq!(merged[0].spans.iter().filter(|span| span.name == "cli outer").count(), 2);
+    assert_eq!(merged[0].frame_marks.len(), 2);
+    assert_eq!(merged[0].marks.iter().filter(|mark| mark.name == "cli mark").count(), 2);
+    assert!(!run(&["filter", path, "--thread", "nobody"]).contains("cli inner"));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn text_report_options() {
+    use flame::{ColorChoice, SortOrder, TextReportOptions, TimeUnit};
+
+    let session = flame::Session::new();
+    session.start("root");
+    session.start("short");
+    session.end("short");
+    session.start("long");
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    session.start("deep");
+    session.end("deep");
+    session.end("long");
+    session.end("root");
+
+    let report = |options: &TextReportOptions| {
+        let mut text = vec![];
+        session.dump_text_with(&mut text, options).unwrap();
+        String::from_utf8(text).unwrap()
+    };
+
+    let plain = report(&TextReportOptions::new());
+    assert!(!plain.contains('\x1b'));
+    assert!(plain.contains("% of parent") && plain.contains("% of total"));
+    assert!(plain.contains('█'));
+
+    let colored = report(&TextReportOptions::new().color(ColorChoice::Always));
+    assert!(colored.contains("\x1b[1mroot\x1b[0m"));
+
+    let sorted = report(&TextReportOptions::new().sort(SortOrder::Time).bars(0)
+                        .unit(TimeUnit::Milliseconds).precision(1));
+    assert!(sorted.find("| long").unwrap() < sorted.find("| short").unwrap());
+    assert!(!sorted.contains('█'));
+    let long = sorted.lines().find(|line| line.starts_with("  | long: ")).unwrap();
+    let time = &long["  | long: ".len() .. long.find("ms").unwrap()];
+    assert_eq!(time.split('.').nth(1).map(str::len), Some(1));
+    assert!(time.parse::<f64>().unwrap() >= 5.0);
+
+    let shallow = report(&TextReportOptions::new().max_depth(1).min_percent(1.0));
+    assert!(shallow.contains("| long"));
+    assert!(!shallow.contains("| short"));
+    assert!(!shallow.contains("| deep"));
+
+    // Without options the report keeps its plain layout.
+    let mut text = vec![];
+    session.dump_text_to_writer(&mut text).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    assert!(text.starts_with("THREAD: ") && text.lines().next().unwrap()["THREAD: ".len() ..].parse::<usize>().is_ok());
+    assert!(!text.contains('█') && !text.contains("% of"));
+    let long = text.lines().find(|line| line.starts_with("  | long: ")).unwrap();
+    let time = long["  | long: ".len() ..].split("ms").next().unwrap();
+    assert!(time.parse::<f64>().unwrap() >= 5.0);
+}
+
+#[test]
+fn svg() {
+    use flame::{Orientation, Palette, SvgOptions};
+
+    let session = flame::Session::new();
+    session.start("root & <co>");
+    session.start("child");
+    std::thread::sleep(std::time::Duration::from_millis(1));
+    session.end("child");
+    session.end("root & <co>");
+    let spans = session.spans();
+
+    let render = |options: &SvgOptions| {
+        let mut svg = vec![];
+        flame::dump_svg(&mut svg, &spans, options).unwrap();
+        String::from_utf8(svg).unwrap()
+    };
+    let flamegraph = render(&SvgOptions::new().title("Profile").width(800));
+    assert!(flamegraph.contains("<svg") && flamegraph.contains("width=\"800\""));
+    assert!(flamegraph.contains("<title>root &amp; &lt;co&gt; ("));
+    assert!(flamegraph.contains(">Profile</text>"));
+    assert!(!flamegraph.contains("<script"));
+
+    let y = |svg: &str, name: &str| -> f64 {
+        let frame = &svg[svg.find(&format!("<title>{} (", name)).unwrap() ..];
+        let y = &frame[frame.find(" y=\"").unwrap() + 4 ..];
+        y[.. y.find('"').unwrap()].parse().unwrap()
+    };
+    assert!(y(&flamegraph, "child") < y(&flamegraph, "root &amp; &lt;co&gt;"));
+    let icicle = render(&SvgOptions::new().orientation(Orientation::Icicle).palette(Palette::Gray).frame_height(20));
+    assert!(y(&icicle, "child") > y(&icicle, "root &amp; &lt;co&gt;"));
+}
+
+#[test]
+fn html_covers_every_thread() {
+    let session = flame::Session::new();
+    session.start("html main");
+    session.end("html main");
+    let worker = session.clone();
+    std::thread::Builder::new().name("html worker".into()).spawn(move || {
+        worker.start("html on worker");
+        worker.end("html on worker");
+    }).unwrap().join().unwrap();
+
+    let mut html = vec![];
+    session.dump_html(&mut html).unwrap();
+    let html = String::from_utf8(html).unwrap();
+    assert!(html.contains("\"html main\""));
+    assert!(html.contains("\"html on worker\""));
+    assert!(html.contains("html worker ("));
+    assert!(html.contains("All threads"));
+}
+
+/// Parses the `var <name> = ...;` data of a page from `dump_html`.
+fn page_data(html: &str, name: &str) -> serde_json::Value {
+    let prefix = format!("var {} = ", name);
+    let line = html.lines().map(str::trim).find(|line| line.starts_with(&prefix)).unwrap();
+    serde_json::from_str(&line[prefix.len()..line.len() - 1]).unwrap()
+}
+
+#[test]
+fn html_shows_notes() {
+    let session = flame::Session::new();
+    session.start("noted");
+    session.note("checkpoint", Some("halfway there"));
+    session.note("bare", None);
+    session.end("noted");
+
+    let mut html = vec![];
+    session.dump_html(&mut html).unwrap();
+    let html = String::from_utf8(html).unwrap();
+    assert!(html.contains("id=\"notes\""));
+
+    let notes = &page_data(&html, "threads")[0]["spans"][0]["notes"];
+    assert_eq!(notes[0]["name"], "checkpoint");
+    assert_eq!(notes[0]["description"], "halfway there");
+    assert!(notes[0]["instant"].is_u64());
+    assert_eq!(notes[1]["name"], "bare");
+    assert!(notes[1]["description"].is_null());
+}
+
+#[test]
+fn html_options() {
+    use flame::{HtmlOptions, Palette, SortOrder};
+
+    let session = flame::Session::new();
+    session.start("</script><script>alert(1)</script>");
+    session.note("smile \u{1f600}", Some("line\u{2028}separator"));
+    session.end("</script><script>alert(1)</script>");
+
+    let options = HtmlOptions::new()
+        .title("A <profile>")
+        .css(".custom-css { color: red; }")
+        .script("console.log('custom script');")
+        .palette(Palette::Mem)
+        .sort(SortOrder::Name);
+    let mut html = vec![];
+    session.dump_html_with(&mut html, &options).unwrap();
+    let html = String::from_utf8(html).unwrap();
+    assert!(html.contains(r#""\u003c/script\u003e\u003cscript\u003ealert(1)\u003c/script\u003e""#));
+    assert!(!html.contains("alert(1)</script>"));
+    assert!(html.contains("\"smile \u{1f600}\""));
+    assert!(html.contains(r#""line\u2028separator""#));
+    assert!(html.contains("<title>A &lt;profile&gt;</title>"));
+    assert!(html.contains(".custom-css { color: red; }"));
+    assert!(html.contains("console.log('custom script');"));
+    assert!(html.contains("var palette = 'mem';"));
+    assert!(html.contains("var sortBy = 'name';"));
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_layer() {
+    use tracing_subscriber::prelude::*;
+
+    let session = flame::Session::new();
+    let subscriber = tracing_subscriber::registry().with(flame::FlameLayer::new());
+    tracing::subscriber::with_default(subscriber, || flame::with_session(&session, || {
+        let outer = tracing::info_span!("outer", id = 7, later = tracing::field::Empty);
+        {
+            let _outer = outer.enter();
+            // Entering it again doesn't start another span.
+            let _again = outer.enter();
+            tracing::info!(rows = 3, "loaded");
+            let _inner = tracing::info_span!("inner").entered();
+            outer.record("later", "yes");
+        }
+
+        // A span that is entered on several threads.
+        let shared = tracing::info_span!("shared");
+        let worker_span = shared.clone();
+        let worker = session.clone();
+        let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+        std::thread::spawn(move || tracing::dispatcher::with_default(&dispatch, || {
+            flame::with_session(&worker, || {
+                worker_span.in_scope(|| {});
+            });
+        })).join().unwrap();
+        shared.in_scope(|| {});
+
+        // Spans that are exited out of order don't panic.
+        let a = tracing::info_span!("a");
+        let b = tracing::info_span!("b");
+        let a_guard = a.enter();
+        let b_guard = b.enter();
+        drop(a_guard);
+        drop(b_guard);
+    }));
+
+    let spans = session.spans();
+    let names: Vec<&str> = spans.iter().map(|span| &span.name[..]).collect();
+    assert_eq!(names, vec!["outer", "shared", "a"]);
+    let outer = &spans[0];
+    assert!(outer.attributes.contains(&("id".to_owned(), "7".to_owned())));
+    assert!(outer.attributes.contains(&("later".to_owned(), "yes".to_owned())));
+    assert!(outer.location.as_ref().unwrap().file.ends_with("tests.rs"));
+    assert_eq!(outer.children.len(), 1);
+    assert_eq!(outer.children[0].name, "inner");
+    assert_eq!(outer.notes.len(), 1);
+    assert_eq!(outer.notes[0].name, "loaded");
+    assert_eq!(outer.notes[0].attributes, vec![("rows".to_owned(), "3".to_owned())]);
+    assert_eq!(spans[2].children[0].name, "b");
+    let threads = session.threads();
+    assert_eq!(threads.len(), 2);
+    assert!(threads.iter().all(|thread| thread.spans.iter().any(|span| span.name == "shared")));
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn clear_keeps_spans_whose_parent_ended() {
+    use tracing_subscriber::prelude::*;
+
+    let session = flame::Session::new();
+    let subscriber = tracing_subscriber::registry().with(flame::FlameLayer::new());
+    tracing::subscriber::with_default(subscriber, || flame::with_session(&session, || {
+        let _outer = tracing::info_span!("outer").entered();
+        let a = tracing::info_span!("a");
+        let b = tracing::info_span!("b");
+        let a_guard = a.enter();
+        let b_guard = b.enter();
+        drop(a_guard);
+        let other = session.clone();
+        std::thread::spawn(move || other.clear()).join().unwrap();
+        let _c = tracing::info_span!("c").entered();
+        drop(_c);
+        drop(b_guard);
+    }));
+
+    let spans = session.spans();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].name, "outer");
+    assert_eq!(spans[0].children.len(), 1);
+    assert_eq!(spans[0].children[0].name, "b");
+    assert_eq!(spans[0].children[0].children[0].name, "c");
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_spans_end_only_themselves() {
+    use tracing_subscriber::prelude::*;
+
+    let first = flame::Session::new();
+    let second = flame::Session::new();
+    let subscriber = tracing_subscriber::registry().with(flame::FlameLayer::new());
+    tracing::subscriber::with_default(subscriber, || flame::with_session(&first, || {
+        // Spans started by hand inside of a tracing span keep running.
+        let span = tracing::info_span!("traced").entered();
+        flame::start("manual");
+        drop(span);
+        flame::end("manual");
+
+        // A span ends in the session it was started in.
+        let span = tracing::info_span!("moved").entered();
+        flame::with_session(&second, || {
+            flame::start("other");
+            drop(span);
+            flame::end("other");
+        });
+    }));
+
+    let spans = first.spans();
+    let names: Vec<&str> = spans.iter().map(|span| &span.name[..]).collect();
+    assert_eq!(names, vec!["traced", "moved"]);
+    assert_eq!(spans[0].children[0].name, "manual");
+    let spans = second.spans();
+    assert_eq!(spans.len(), 1);
+    assert_eq!(spans[0].name, "other");
+}
+
+#[cfg(feature = "log")]
+#[test]
+fn log_records_become_notes() {
+    use std::sync::{Arc, Mutex};
+    use log::Log;
+
+    struct Collect(Arc<Mutex<Vec<String>>>);
+    impl log::Log for Collect {
+        fn enabled(&self, _: &log::Metadata) -> bool { true }
+        fn log(&self, record: &log::Record) { self.0.lock().unwrap().push(record.args().to_string()); }
+        fn flush(&self) {}
+    }
+
+    let passed = Arc::new(Mutex::new(vec![]));
+    let logger = flame::FlameLogger::new(Collect(passed.clone())).level(log::LevelFilter::Warn);
+    let record = |level, message: &str| {
+        logger.log(&log::Record::builder()
+            .args(format_args!("{}", message))
+            .level(level)
+            .target("db")
+            .file(Some("src/db.rs"))
+            .line(Some(12))
+            .build());
+    };
+
+    let session = flame::Session::new();
+    flame::with_session(&session, || {
+        record(log::Level::Warn, "before any span");
+        flame::start("query");
+        record(log::Level::Error, "retrying");
+        record(log::Level::Info, "too quiet");
+        flame::end("query");
+    });
+
+    assert_eq!(*passed.lock().unwrap(), vec!["before any span", "retrying", "too quiet"]);
+    let threads = session.threads();
+    assert_eq!(threads.len(), 1);
+    let thread = &threads[0];
+    assert_eq!(thread.notes.len(), 1);
+    assert_eq!(thread.notes[0].name, "before any span");
+    let notes = &thread.spans[0].notes;
+    assert_eq!(notes.len(), 1);
+    assert_eq!(notes[0].name, "retrying");
+    assert_eq!(notes[0].attributes, vec![("level".to_owned(), "ERROR".to_owned()),
+                                         ("target".to_owned(), "db".to_owned())]);
+    assert_eq!(notes[0].location.as_ref().unwrap().to_string(), "src/db.rs:12");
+}
+
+#[test]
+fn spawned_threads_know_their_parent() {
+    let session = flame::Session::new();
+    let main_id = flame::with_session(&session, || {
+        let _guard = flame::start_guard("fan out");
+        flame::spawn("spawned", || flame::span_of("on spawned", || ())).join().unwrap();
+
+        let mut results = [0; 2];
+        flame::scope(|scope| {
+            for (i, result) in results.iter_mut().enumerate() {
+                scope.spawn(format!("scoped {}", i), move || {
+                    flame::span_of("on scoped", || *result = i + 1);
+                });
+            }
+        });
+        assert_eq!(results, [1, 2]);
+
+        let context = flame::Context::current();
+        std::thread::spawn(move || context.attach(|| flame::span_of("on pool", || ()))).join().unwrap();
+        flame::threads()[0].id
+    });
+
+    let threads = session.threads();
+    assert_eq!(threads.len(), 5);
+    let fan_out = &threads[0].spans[0];
+    for thread in &threads[1 ..] {
+        let parent = thread.parent.as_ref().unwrap();
+        assert_eq!(parent.thread_id, main_id);
+        assert_eq!(parent.span.as_ref().unwrap(), "fan out");
+        assert_eq!(parent.span_start_ns, Some(fan_out.start_ns));
+        assert_eq!(thread.spans.len(), 1);
+    }
+    let names: Vec<Option<&str>> = threads[1 ..].iter().map(|thread| thread.name.as_deref()).collect();
+    assert!(names.contains(&Some("spawned")));
+    assert!(names.contains(&Some("scoped 0")));
+    assert!(names.contains(&Some("scoped 1")));
+    assert!(threads[0].parent.is_none());
+}
+
+#[test]
+fn overlapping_spans() {
+    let session = flame::Session::new();
+    let (first, second, third) = flame::with_session(&session, || {
+        let first = flame::begin("request 1");
+        flame::start("on the stack");
+        let second = flame::begin("request 2");
+        flame::end("on the stack");
+        flame::finish(first);
+        let third = flame::begin("request 3");
+        flame::finish(third);
+        // Still running, and mark_frame must not drop it.
+        flame::frame_mark();
+        (first, second, third)
+    });
+    assert_ne!(first, second);
+    assert_ne!(second, third);
+
+    let threads = session.threads_with(&flame::SnapshotOptions::new().include_open(true));
+    let thread = &threads[0];
+    assert_eq!(thread.spans.len(), 1);
+    let lanes: Vec<(&str, Option<u32>, bool)> = thread.async_spans.iter()
+        .map(|span| (&span.name[..], span.lane, span.is_open))
+        .collect();
+    assert_eq!(lanes, vec![("request 1", Some(0), false), ("request 2", Some(1), true), ("request 3", Some(0), false)]);
+    let frames = session.frames();
+    assert_eq!(frames[0].spans[0].name, "on the stack");
+    let finished: Vec<(&str, Option<u32>)> = frames[0].async_spans.iter()
+        .map(|span| (&span.name[..], span.lane))
+        .collect();
+    assert_eq!(finished, vec![("request 1", Some(0)), ("request 3", Some(0))]);
+
+    session.finish(second);
+    let threads = session.threads();
+    assert_eq!(threads[0].async_spans.len(), 3);
+    assert!(!threads[0].async_spans[1].is_open);
+
+    let mut timeline = vec![];
+    flame::dump_timeline_custom(&mut timeline, &threads).unwrap();
+    assert!(String::from_utf8(timeline).unwrap().contains("request 2"));
+
+    // Every exporter shows the async lanes.
+    let mut folded = vec![];
+    flame::dump_folded(&mut folded, &threads).unwrap();
+    let folded = String::from_utf8(folded).unwrap();
+    assert!(folded.lines().any(|line| line.starts_with("async lane 1;request 2 ")));
+    assert!(folded.lines().any(|line| line.starts_with("async lane 0;request 3 ")));
+    let mut svg = vec![];
+    flame::dump_svg_threads(&mut svg, &threads, &flame::SvgOptions::new()).unwrap();
+    let svg = String::from_utf8(svg).unwrap();
+    assert!(svg.contains("<title>async lane 1 (") && svg.contains("<title>request 2 ("));
+    let mut html = vec![];
+    flame::dump_html_threads(&mut html, &threads).unwrap();
+    let html = String::from_utf8(html).unwrap();
+    assert!(html.contains("async lane 1") && html.contains("request 2"));
+    #[cfg(feature = "json")]
+    {
+        let mut speedscope = vec![];
+        flame::dump_speedscope(&mut speedscope, &threads).unwrap();
+        let speedscope = String::from_utf8(speedscope).unwrap();
+        assert!(speedscope.contains("async lane 0\"") && speedscope.contains("async lane 1\""));
+        assert!(speedscope.contains("\"request 2\""));
+    }
+
+    let overlapping = flame::Session::new();
+    let a = overlapping.begin("a");
+    let b = overlapping.begin("b");
+    overlapping.finish(a);
+    let c = overlapping.begin("c");
+    overlapping.finish(b);
+    overlapping.finish(c);
+    let threads = overlapping.threads();
+    let lanes: Vec<Option<u32>> = threads[0].async_spans.iter().map(|span| span.lane).collect();
+    assert_eq!(lanes, vec![Some(0), Some(1), Some(0)]);
+}
+
+#[test]
+#[should_panic]
+fn finish_twice() {
+    let session = flame::Session::new();
+    let id = session.begin("once");
+    session.finish(id);
+    session.finish(id);
+}
+
+#[test]
+fn marks() {
+    let session = flame::Session::new();
+    session.start("before");
+    session.end("before");
+    flame::mark("config reloaded");
+    session.note("on the thread", Some("no span is running"));
+
+    let marks: Vec<flame::Mark> = session.marks().into_iter()
+        .filter(|mark| mark.name == "config reloaded")
+        .collect();
+    assert_eq!(marks.len(), 1);
+    let threads = session.threads();
+    assert!(marks[0].instant >= threads[0].spans[0].end_ns);
+    assert!(marks[0].instant <= threads[0].notes[0].instant);
+    // Marks are part of the snapshot, on the thread that made them.
+    assert!(threads[0].marks.iter().any(|mark| mark.name == "config reloaded"));
+
+    let mut timeline = vec![];
+    flame::dump_timeline_custom(&mut timeline, &threads).unwrap();
+    let timeline = String::from_utf8(timeline).unwrap();
+    assert!(timeline.contains(r#"<title>config reloaded</title><line class="mark""#));
+    assert!(timeline.contains("<title>on the thread: no span is running</title>"));
+
+    let mut html = vec![];
+    session.dump_html(&mut html).unwrap();
+    let marks = page_data(&String::from_utf8(html).unwrap(), "marks");
+    let mark = marks.as_array().unwrap().iter().find(|mark| mark["name"] == "config reloaded").unwrap();
+    assert!(mark["description"].is_null());
+
+    #[cfg(feature = "json")]
+    {
+        let mut chrome = vec![];
+        flame::dump_chrome(&mut chrome, &threads).unwrap();
+        let chrome = String::from_utf8(chrome).unwrap();
+        assert!(chrome.contains(r#""name":"config reloaded","ph":"i""#));
+        assert!(chrome.contains(r#""s":"g""#));
+
+        let mut json = vec![];
+        session.dump_json(&mut json).unwrap();
+        let loaded = flame::load_json(&json[..]).unwrap();
+        assert!(loaded[0].marks.iter().any(|mark| mark.name == "config reloaded"));
+    }
+
+    // Sessions only see the marks that were made after they started or
+    // were cleared.
+    assert!(flame::Session::new().marks().iter().all(|mark| mark.name != "config reloaded"));
+    session.clear();
+    flame::mark("after clear");
+    let names: Vec<_> = session.marks().into_iter().map(|mark| mark.name).collect();
+    assert!(!names.iter().any(|name| name == "config reloaded"));
+    assert!(names.iter().any(|name| name == "after clear"));
+
+    // Only the newest marks are kept.
+    for _ in 0 .. flame::MAX_MARKS {
+        flame::mark("bounded");
+    }
+    let marks = session.marks();
+    assert!(marks.len() <= flame::MAX_MARKS);
+    assert!(marks.iter().all(|mark| mark.name != "after clear"));
 }